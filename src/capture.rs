@@ -2,17 +2,20 @@ use crossbeam::{
     channel::{self, Receiver, Sender},
     select,
 };
-use etherparse::{Ipv4Header, SlicedPacket, TcpHeader};
+use etherparse::{EtherType, SlicedPacket, TransportSlice};
 use log::{error, info};
-use pcap::{Active, Capture, Device};
+use pcap::{Activated, Capture, Linktype};
 use std::{
-    fs::File,
-    io::BufWriter,
+    path::PathBuf,
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    stream::{Endpoint, EndpointSide, TcpStream},
+    config::CaptureConfig,
+    config::PortRange,
+    savefile::{self, RawPacket},
+    stream::{self, Endpoint, EndpointSide, KeyLog, Schema, TcpStream},
     ui::stream::HttpStream,
 };
 
@@ -21,15 +24,36 @@ use crate::{
 /// conversation between the two endpoints or it is something new.
 #[derive(Debug)]
 struct Streams {
+    /// The name of the interface or file the streams are captured on.
+    interface: String,
     next_id: usize,
     streams: Vec<TcpStream>,
+    /// Streams without packets for this long are evicted.
+    idle_timeout: Duration,
+    /// The maximum number of streams tracked at the same time.
+    max_streams: usize,
+    /// The ports of the servers, to find out the client side of a connection.
+    server_ports: Vec<PortRange>,
+    /// The number of streams evicted before they were finished.
+    evicted: usize,
+    /// The TLS secrets, the TLS connections are decrypted if there is a key log file.
+    keylog: Option<KeyLog>,
+    /// The protobuf message types of the gRPC calls.
+    schema: Option<Schema>,
 }
 
 impl Streams {
-    fn new() -> Self {
+    fn new(interface: String, config: &CaptureConfig, schema: Option<Schema>) -> Self {
         Self {
+            interface,
             next_id: 0,
             streams: vec![],
+            idle_timeout: config.idle_timeout,
+            max_streams: config.max_streams,
+            server_ports: config.ports.clone(),
+            evicted: 0,
+            keylog: config.keylog_file.clone().map(KeyLog::new),
+            schema,
         }
     }
 
@@ -52,36 +76,135 @@ impl Streams {
         None
     }
 
-    /// Stores a new stream in the streams vector.
-    fn store(&mut self, source: Endpoint, dest: Endpoint, ts: i64) -> (usize, EndpointSide) {
-        match self.lookup_stream(&source, &dest) {
+    /// Find out if the sender of the packet is the client of the connection. The handshake
+    /// tells that for sure, otherwise the server ports and the HTTP messages are checked.
+    /// The second value is true, if the direction is just a guess.
+    fn sent_by_client(&self, packet: &FilteredStream) -> (bool, bool) {
+        if packet.syn {
+            // SYN comes from the client, SYN-ACK from the server
+            return (packet.ack.is_none(), false);
+        }
+
+        let is_server_port = |port| self.server_ports.iter().any(|p| p.contains(port));
+
+        match (
+            is_server_port(packet.src.port()),
+            is_server_port(packet.dest.port()),
+        ) {
+            (false, true) => return (true, false),
+            (true, false) => return (false, false),
+            _ => {}
+        }
+
+        match stream::sent_by_client(&packet.payload) {
+            Some(client) => (client, false),
+            None => (true, true),
+        }
+    }
+
+    /// Stores a new stream in the streams vector with the client as the source side, and
+    /// gives back which side sent the packet. If there are too many streams, the least
+    /// recently active one is evicted, and the exchanges parsed from it are given back.
+    ///
+    /// Segments without SYN and payload don't start a new stream, like the last ACK or a
+    /// repeated RST of a connection which has been closed already.
+    fn store(&mut self, packet: &FilteredStream) -> Option<(usize, EndpointSide, Vec<HttpStream>)> {
+        let (source, dest) = (&packet.src, &packet.dest);
+
+        match self.lookup_stream(source, dest) {
+            None if !packet.syn && packet.payload.is_empty() => None,
             None => {
-                let stream = TcpStream::new(self.next_id, ts, source, dest);
+                let mut evicted = vec![];
+
+                if self.streams.len() >= self.max_streams {
+                    let oldest = self
+                        .streams
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, stream)| stream.last_seen())
+                        .map(|(i, _)| i);
+
+                    if let Some(index) = oldest {
+                        evicted = self.send_stream(index);
+                        self.evicted += 1;
+                    }
+                }
+
+                let (client, guessed) = self.sent_by_client(packet);
+                let (stream, side) = if client {
+                    let stream = TcpStream::new(
+                        self.next_id,
+                        packet.ts,
+                        source.clone(),
+                        dest.clone(),
+                        guessed,
+                    );
+
+                    (stream, EndpointSide::Source)
+                } else {
+                    let stream = TcpStream::new(
+                        self.next_id,
+                        packet.ts,
+                        dest.clone(),
+                        source.clone(),
+                        guessed,
+                    );
+
+                    (stream, EndpointSide::Destination)
+                };
 
                 self.streams.push(stream);
                 self.next_id += 1;
 
-                (self.streams.len() - 1, EndpointSide::Source)
+                Some((self.streams.len() - 1, side, evicted))
+            }
+            Some((index, side)) => {
+                let stream = &mut self.streams[index];
+
+                if !stream.is_direction_guessed() {
+                    return Some((index, side, vec![]));
+                }
+
+                // The first HTTP message tells which side is the client
+                match (stream::sent_by_client(&packet.payload), &side) {
+                    (Some(false), EndpointSide::Source) => {
+                        stream.swap_direction();
+
+                        Some((index, EndpointSide::Destination, vec![]))
+                    }
+                    (Some(true), EndpointSide::Destination) => {
+                        stream.swap_direction();
+
+                        Some((index, EndpointSide::Source, vec![]))
+                    }
+                    (Some(_), _) => {
+                        stream.confirm_direction();
+
+                        Some((index, side, vec![]))
+                    }
+                    (None, _) => Some((index, side, vec![])),
+                }
             }
-            Some(ep) => ep,
         }
     }
 
-    fn append_request_bytes(&mut self, index: usize, bytes: &[u8]) {
+    fn add_segment(&mut self, index: usize, side: &EndpointSide, packet: &FilteredStream) {
+        let (seq, syn, ack, payload) = (packet.seq, packet.syn, packet.ack, &packet.payload);
+
         match self.streams.get_mut(index) {
-            Some(stream) => stream.append_request_bytes(bytes),
+            Some(stream) => match side {
+                EndpointSide::Source => stream.add_request_segment(seq, syn, ack, payload),
+                EndpointSide::Destination => stream.add_response_segment(seq, syn, ack, payload),
+            },
             None => {
                 panic!("Index {} cannot be found", index);
             }
         }
     }
 
-    fn append_response_bytes(&mut self, index: usize, bytes: &[u8]) {
-        match self.streams.get_mut(index) {
-            Some(stream) => stream.append_response_bytes(bytes),
-            None => {
-                panic!("Index {} cannot be found", index);
-            }
+    fn add_packet(&mut self, index: usize, packet: RawPacket) {
+        if let Some(stream) = self.streams.get_mut(index) {
+            stream.add_packet(packet);
         }
     }
 
@@ -98,10 +221,85 @@ impl Streams {
         }
     }
 
+    /// Parse the exchanges of the stream which are complete, while the connection is open.
+    fn take_exchanges(&mut self, index: usize) -> Vec<HttpStream> {
+        match self.streams.get_mut(index) {
+            Some(stream) => {
+                if let Some(ref mut keylog) = self.keylog {
+                    stream.decrypt(keylog);
+                }
+
+                let exchanges = stream.take_exchanges(&self.interface);
+
+                self.decode_grpc(exchanges)
+            }
+            None => vec![],
+        }
+    }
+
     fn send_stream(&mut self, index: usize) -> Vec<HttpStream> {
-        let stream = self.streams.remove(index);
+        let mut stream = self.streams.remove(index);
+
+        if let Some(ref mut keylog) = self.keylog {
+            stream.decrypt(keylog);
+        }
+
+        let exchanges = stream.convert_to_http_stream(&self.interface);
 
-        stream.convert_to_http_stream()
+        self.decode_grpc(exchanges)
+    }
+
+    /// Decode the messages of the gRPC calls with the schema, or without it.
+    fn decode_grpc(&self, mut exchanges: Vec<HttpStream>) -> Vec<HttpStream> {
+        for exchange in &mut exchanges {
+            stream::decode_grpc(exchange, self.schema.as_ref());
+        }
+
+        exchanges
+    }
+
+    /// Emit the exchanges in progress whose responses have grown since their last update,
+    /// when packets don't arrive to trigger it.
+    fn live_updates(&mut self, now: Duration) -> Vec<HttpStream> {
+        let exchanges = self
+            .streams
+            .iter_mut()
+            .filter_map(|stream| stream.live_update(&self.interface, now))
+            .collect();
+
+        self.decode_grpc(exchanges)
+    }
+
+    /// Evict the streams which have been idle for longer than the idle timeout, and
+    /// convert them to http streams. Connections without FIN or RST can be abandoned
+    /// forever.
+    fn evict_idle(&mut self, now: Duration) -> Vec<HttpStream> {
+        let mut http_streams = vec![];
+        let mut i = 0;
+
+        while i < self.streams.len() {
+            if now.saturating_sub(self.streams[i].last_seen()) > self.idle_timeout {
+                http_streams.extend(self.send_stream(i));
+                self.evicted += 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        http_streams
+    }
+
+    /// Removes all the streams regardless of they are finished or not, and converts
+    /// them to http streams. It is used when there won't be more packets, like at the
+    /// end of a capture file.
+    fn drain(&mut self) -> Vec<HttpStream> {
+        let mut http_streams = vec![];
+
+        while !self.streams.is_empty() {
+            http_streams.extend(self.send_stream(0));
+        }
+
+        http_streams
     }
 }
 
@@ -109,37 +307,184 @@ pub enum Command {
     StopCapture,
 }
 
+/// Messages sent by the capture threads to the UI.
+#[derive(Debug)]
+pub enum Event {
+    Stream(Box<HttpStream>),
+    /// The number of streams evicted from the flow table of the capture so far, because
+    /// they were idle or the table was full.
+    Evicted {
+        source: String,
+        count: usize,
+    },
+    /// The capture is opened, the filter of the next captures is checked against its link
+    /// type (the DLT value of libpcap).
+    Started {
+        source: String,
+        capture: usize,
+        datalink: Linktype,
+    },
+    /// The capture is over, because it was stopped, the end of the file has been
+    /// reached or the capture could not be started at all.
+    Finished {
+        source: String,
+        /// The id of the capture given when it was started.
+        capture: usize,
+        error: Option<CaptureError>,
+    },
+}
+
+/// Why a capture could not be started or went wrong.
+#[derive(Debug)]
+pub enum CaptureError {
+    /// The filter cannot be used on the link type of the capture.
+    Filter(String),
+    Other(String),
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::Filter(e) => write!(f, "Invalid filter: {e}"),
+            CaptureError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+/// Where the packets come from: a network interface or a pcap/pcapng file.
+#[derive(Clone, Debug)]
+pub enum CaptureSource {
+    Device(String),
+    File(PathBuf),
+}
+
+impl std::fmt::Display for CaptureSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureSource::Device(name) => write!(f, "{name}"),
+            CaptureSource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 pub fn start_capture(
-    interface: String,
-    port: u16,
-    output: Sender<HttpStream>,
+    source: CaptureSource,
+    config: CaptureConfig,
+    id: usize,
+    output: Sender<Event>,
     commands: Receiver<Command>,
 ) -> JoinHandle<()> {
-    let mut devices = Device::list().unwrap();
-    let i = devices.iter().position(|d| d.name == interface).unwrap();
-    let device = devices.remove(i);
-
     thread::spawn(move || {
-        let device_name = device.name.to_string();
+        info!("Start capturing on {source}");
 
-        info!("Start capturing on {device:?}");
+        let error = match open_and_capture(&source, id, &config, &output, commands) {
+            Ok(()) => None,
+            Err(e) => {
+                error!("Error capturing on {source}: {e:?}");
 
-        capture_loop(device, port, output, commands);
+                match e.downcast::<CaptureError>() {
+                    Ok(e) => Some(*e),
+                    Err(e) => Some(CaptureError::Other(e.to_string())),
+                }
+            }
+        };
+
+        let finished = Event::Finished {
+            source: source.to_string(),
+            capture: id,
+            error,
+        };
+
+        if let Err(e) = output.send(finished) {
+            error!("Error {e:?}");
+        }
 
-        info!("Stop capturing on {device_name}");
+        info!("Stop capturing on {source}");
     })
 }
 
-// TODO here we need to store the packets for saving
-// once we write the packets in a file (after a successful
-// matching to the filters) we should drop the packets
-// and keep the other fiels from the FilteredStream
+/// The link types a filter is checked against when no capture has been opened yet.
+const COMMON_DATALINKS: [Linktype; 3] = [
+    Linktype::ETHERNET,
+    Linktype::LINUX_SLL,
+    Linktype::LINUX_SLL2,
+];
+
+/// Compile the BPF filter expression for the link types (DLT values) of the captures to
+/// see if it is valid. Without link types it needs to be valid on one of the common ones,
+/// the capture checks it again on its own link type.
+pub fn check_filter(filter: &str, datalinks: &[Linktype]) -> Result<(), String> {
+    let compile = |datalink: Linktype| -> Result<(), pcap::Error> {
+        Capture::dead(datalink)?.compile(filter, true)?;
+
+        Ok(())
+    };
+
+    if datalinks.is_empty() {
+        let mut error = None;
+
+        for datalink in COMMON_DATALINKS {
+            match compile(datalink) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    error.get_or_insert(e.to_string());
+                }
+            }
+        }
+
+        return Err(error.unwrap_or_default());
+    }
+
+    for datalink in datalinks {
+        compile(*datalink).map_err(|e| {
+            let name = datalink.get_name().unwrap_or(datalink.0.to_string());
+
+            format!("{e} (link type {name})")
+        })?;
+    }
+
+    Ok(())
+}
+
+fn open_and_capture(
+    source: &CaptureSource,
+    id: usize,
+    config: &CaptureConfig,
+    output: &Sender<Event>,
+    commands: Receiver<Command>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match source {
+        CaptureSource::Device(name) => {
+            let cap = Capture::from_device(name.as_str())?
+                .immediate_mode(true)
+                .open()?;
+
+            capture_loop(name, id, cap, config, output, commands)
+        }
+        CaptureSource::File(path) => {
+            let cap = Capture::from_file(path)?;
+
+            capture_loop(&source.to_string(), id, cap, config, output, commands)
+        }
+    }
+}
+
+/// A tcp packet which passed the filters. Besides the parsed fields it keeps the
+/// original packet, so the stream can be saved to a capture file.
 struct FilteredStream {
     ts: i64,
     src: Endpoint,
     dest: Endpoint,
     payload: Vec<u8>,
+    seq: u32,
+    syn: bool,
+    /// Acknowledgement number if the ACK flag is set
+    ack: Option<u32>,
     fin: bool,
+    rst: bool,
+    packet: RawPacket,
 }
 
 impl std::fmt::Debug for FilteredStream {
@@ -149,46 +494,99 @@ impl std::fmt::Debug for FilteredStream {
             .field("dest", &self.dest)
             .field("payload", &self.payload.len())
             .field("fin", &self.fin)
+            .field("rst", &self.rst)
             .finish()
     }
 }
 
-fn from_loopback_packet(data: &[u8]) -> (Ipv4Header, TcpHeader, &[u8]) {
-    if data.starts_with(&[2, 0, 0, 0]) {
-        // The next 20 bytes is the IP header
-        let (ip4_header, _rest) = Ipv4Header::from_slice(&data[4..24]).unwrap();
-        let (transport_header, rest) = TcpHeader::from_slice(&data[24..]).unwrap();
+/// The link layer types the packets can be parsed from, as LINKTYPE numbers.
+const SUPPORTED_LINKTYPES: [Linktype; 8] = [
+    Linktype::ETHERNET,
+    Linktype::LINUX_SLL,
+    Linktype::LINUX_SLL2,
+    Linktype::RAW,
+    Linktype::IPV4,
+    Linktype::IPV6,
+    Linktype::NULL,
+    Linktype::LOOP,
+];
+
+/// Slice the packet according to the link layer type of the capture.
+fn slice_packet(linktype: Linktype, data: &[u8]) -> Result<SlicedPacket<'_>, String> {
+    let packet = match linktype {
+        Linktype::ETHERNET => SlicedPacket::from_ethernet(data),
+        Linktype::LINUX_SLL => SlicedPacket::from_linux_sll(data),
+        Linktype::LINUX_SLL2 => {
+            // SLL2 header is 20 bytes long and starts with the protocol type
+            if data.len() < 20 {
+                return Err("Truncated SLL2 header".to_string());
+            }
 
-        (ip4_header, transport_header, rest)
-    } else {
-        error!("Unknown sequence {}", data[0]);
-        panic!("Cannot parse bytes");
-    }
+            let ether_type = EtherType(u16::from_be_bytes([data[0], data[1]]));
+
+            SlicedPacket::from_ether_type(ether_type, &data[20..])
+        }
+        Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => SlicedPacket::from_ip(data),
+        Linktype::NULL | Linktype::LOOP => {
+            // The 4 bytes address family is in host byte order (NULL) or in network byte order
+            // (LOOP), but the IP version can be found out from the IP header anyway.
+            if data.len() < 4 {
+                return Err("Truncated loopback header".to_string());
+            }
+
+            SlicedPacket::from_ip(&data[4..])
+        }
+        _ => return Err(format!("Unsupported link type {linktype:?}")),
+    };
+
+    packet.map_err(|e| e.to_string())
 }
 
-fn packet_stream(mut cap: Capture<Active>, loopback: bool) -> Receiver<FilteredStream> {
+fn packet_stream<T: Activated + ?Sized + 'static>(mut cap: Capture<T>) -> Receiver<FilteredStream> {
     let (tx, rx) = channel::bounded(5);
+    let linktype = savefile::file_linktype(cap.get_datalink());
 
     thread::spawn(move || {
         info!("Start to stream packets");
 
-        while let Ok(packet) = cap.next_packet() {
-            //hexdump(packet.data);
+        loop {
+            let packet = match cap.next_packet() {
+                Ok(packet) => packet,
+                Err(pcap::Error::NoMorePackets) => break,
+                Err(e) => {
+                    error!("Error reading packet {e:?}");
+
+                    break;
+                }
+            };
+
             //info!("Packet {:?}", packet.header);
 
             let ts = packet.header.ts.tv_sec;
+            let raw_packet = RawPacket::new(linktype, &packet);
 
-            if loopback {
-                let (ip4, tcp, payload) = from_loopback_packet(packet.data);
-                let tcp_fin = tcp.fin;
+            let sliced_packet = match slice_packet(linktype, packet.data) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Cannot parse packet: {e}");
 
-                if let Some((source, dest)) = TcpStream::extract_endpoint_pair(ip4, tcp) {
+                    continue;
+                }
+            };
+
+            if let Some((source, dest)) = TcpStream::from_sliced_packet(&sliced_packet) {
+                if let Some(TransportSlice::Tcp(tcp)) = sliced_packet.transport {
                     let filtered_stream = FilteredStream {
                         ts,
                         src: source,
                         dest,
-                        payload: payload.to_vec(),
-                        fin: tcp_fin,
+                        payload: tcp.payload().to_vec(),
+                        seq: tcp.sequence_number(),
+                        syn: tcp.syn(),
+                        ack: tcp.ack().then(|| tcp.acknowledgment_number()),
+                        fin: tcp.fin(),
+                        rst: tcp.rst(),
+                        packet: raw_packet,
                     };
 
                     if let Err(e) = tx.send(filtered_stream) {
@@ -197,28 +595,6 @@ fn packet_stream(mut cap: Capture<Active>, loopback: bool) -> Receiver<FilteredS
                         break;
                     }
                 }
-            } else {
-                let packet = SlicedPacket::from_ethernet(packet.data).unwrap();
-
-                if let Some((source, dest)) = TcpStream::from_sliced_packet(&packet) {
-                    // TODO implement filtering here
-
-                    if let Some(etherparse::TransportSlice::Tcp(tcp)) = packet.transport {
-                        let filtered_stream = FilteredStream {
-                            ts,
-                            src: source,
-                            dest,
-                            payload: tcp.payload().to_vec(),
-                            fin: tcp.fin(),
-                        };
-
-                        if let Err(e) = tx.send(filtered_stream) {
-                            error!("Error during sending {e:?}");
-
-                            break;
-                        }
-                    }
-                }
             }
         }
 
@@ -228,121 +604,141 @@ fn packet_stream(mut cap: Capture<Active>, loopback: bool) -> Receiver<FilteredS
     rx
 }
 
-#[cfg(target_os = "macos")]
-fn is_loopback(device: &Device) -> bool {
-    device.flags.is_loopback()
-}
+fn capture_loop<T: Activated + ?Sized + 'static>(
+    interface: &str,
+    id: usize,
+    mut cap: Capture<T>,
+    config: &CaptureConfig,
+    output: &Sender<Event>,
+    commands: Receiver<Command>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let datalink = cap.get_datalink();
 
-#[cfg(not(target_os = "macos"))]
-fn is_loopback(_: &Device) -> bool {
-    false
-}
+    if !SUPPORTED_LINKTYPES.contains(&savefile::file_linktype(datalink)) {
+        let name = datalink.get_name().unwrap_or(datalink.0.to_string());
 
-fn capture_loop(
-    device: Device,
-    port: u16,
-    output: Sender<HttpStream>,
-    commands: Receiver<Command>,
-) {
-    // Loopback packets are parsed somehow else
-    let loopback = is_loopback(&device);
-
-    let mut cap = match Capture::from_device(device)
-        .unwrap()
-        .immediate_mode(true)
-        .open()
-    {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Error {e:?}");
+        return Err(format!("Unsupported link type {name}").into());
+    }
 
-            return;
-        }
+    let filter = config.bpf_filter();
+
+    info!("Capture filter: {filter}");
+
+    cap.filter(&filter, true)
+        .map_err(|e| CaptureError::Filter(e.to_string()))?;
+
+    let started = Event::Started {
+        source: interface.to_string(),
+        capture: id,
+        datalink,
     };
 
-    if let Err(e) = cap.filter(format!("tcp port {port}").as_str(), true) {
+    if let Err(e) = output.send(started) {
         error!("Error {e:?}");
-
-        return;
     }
 
-    let mut streams = Streams::new();
+    let schema = match config.descriptor_set {
+        Some(ref path) => Some(Schema::load(path)?),
+        None => None,
+    };
 
-    let packets = packet_stream(cap, loopback);
+    let mut streams = Streams::new(interface.to_string(), config, schema);
+    let mut evicted = 0;
+    // The timestamp of the last packet and when it was received, to know the time of the
+    // capture when there are no packets
+    let mut last_packet: Option<(Duration, Instant)> = None;
+
+    let packets = packet_stream(cap);
+    let ticker = channel::tick(Duration::from_secs(1));
 
     loop {
-        select! {
+        let stream_list = select! {
             recv(packets) -> packet => {
-                if packet.is_err() {
-                    error!("Error during capture loop: {:?}", packet);
+                let Ok(packet) = packet else {
+                    // The packet stream is closed, at the end of a file it is normal,
+                    // so let us show what we have so far.
+                    info!("Packet stream closed, flushing streams");
 
-                    return;
-                }
+                    send_streams(output, id, streams.drain());
 
-                let packet = packet.unwrap();
+                    return Ok(());
+                };
 
-                let (index, side) = streams.store(packet.src, packet.dest, packet.ts);
+                last_packet = Some((packet.packet.timestamp, Instant::now()));
 
-                match side {
-                    EndpointSide::Source => streams.append_request_bytes(index, packet.payload.as_slice()),
-                    EndpointSide::Destination => streams.append_response_bytes(index, packet.payload.as_slice()),
-                }
+                let Some((index, side, mut stream_list)) = streams.store(&packet) else {
+                    continue;
+                };
 
-                // TODO Store if tcp fin came from source or dest side and mark that stream only, not take
-                // that. And also send to the stream.
-                // Rename struct, a lot of has name stream.
-                if packet.fin && streams.register_fin(index, side) {
-                    let stream_list = streams.send_stream(index);
+                streams.add_segment(index, &side, &packet);
+                streams.add_packet(index, packet.packet);
 
-                    //println!("{:?}", streams);
+                // Rename struct, a lot of has name stream.
+                if packet.rst {
+                    // The connection is aborted, no more packets are coming
+                    stream_list.extend(streams.send_stream(index));
+                } else if packet.fin && streams.register_fin(index, side) {
+                    stream_list.extend(streams.send_stream(index));
+                } else {
+                    // Keep-alive connections can carry many exchanges, show them as
+                    // soon as they are complete
+                    stream_list.extend(streams.take_exchanges(index));
+                }
 
-                    for stream in stream_list {
-                        if let Err(e) = output.send(stream) {
-                            error!("Error {e:?}");
-                        }
+                stream_list
+            }
+            recv(ticker) -> _ => {
+                match last_packet {
+                    Some((ts, received)) => {
+                        let now = ts + received.elapsed();
+                        let mut stream_list = streams.live_updates(now);
+
+                        stream_list.extend(streams.evict_idle(now));
+                        stream_list
                     }
+                    None => vec![],
                 }
             }
             recv(commands) -> cmd => {
                 match cmd {
                     Ok(Command::StopCapture) => {
-                        return;
+                        // Show the exchanges in progress as they are
+                        send_streams(output, id, streams.drain());
+
+                        return Ok(());
                     }
                     Err(e) => {
                         error!("Error {e:?}");
 
-                        return;
+                        return Ok(());
                     }
                 }
             }
-        }
-    }
-}
-
-#[allow(dead_code)]
-fn hexdump(data: &[u8]) {
-    use std::io::Write;
+        };
 
-    let f = File::create("packet-hexdump.txt").unwrap();
-    let mut writer = BufWriter::new(f);
+        send_streams(output, id, stream_list);
 
-    for ch in data.chunks(16) {
-        let mut line = String::from("");
+        if streams.evicted != evicted {
+            evicted = streams.evicted;
 
-        for c in ch {
-            writer.write_all(format!("{:02X} ", c).as_bytes()).unwrap();
+            let event = Event::Evicted {
+                source: interface.to_string(),
+                count: evicted,
+            };
 
-            if *c > 31 && *c < 128 {
-                line.push(char::from_u32(*c as u32).unwrap_or('.'));
-            } else {
-                line.push('.');
+            if let Err(e) = output.send(event) {
+                error!("Error {e:?}");
             }
         }
-
-        writer
-            .write_all(format!("|{}|\n", line).as_bytes())
-            .unwrap();
     }
+}
 
-    writer.write_all("\n".as_bytes()).unwrap();
+fn send_streams(output: &Sender<Event>, id: usize, streams: Vec<HttpStream>) {
+    for mut stream in streams {
+        stream.capture = id;
+
+        if let Err(e) = output.send(Event::Stream(Box::new(stream))) {
+            error!("Error {e:?}");
+        }
+    }
 }
@@ -1,40 +1,73 @@
+use std::collections::HashMap;
+
 use crossbeam::channel::{self, Receiver, Sender};
-use log::info;
+use log::{error, info};
 
-use crate::{capture, ui::stream::HttpStream};
+use crate::{
+    capture::{self, CaptureSource, Event},
+    config::CaptureConfig,
+};
 
 #[derive(Debug)]
 pub enum Command {
-    StartCapture(String),
-    StopCapture,
+    /// Start a capture, the id tells its events apart from the ones of the earlier
+    /// captures on the same source.
+    StartCapture(CaptureSource, CaptureConfig, usize),
+    /// Stop the capture of one interface or capture file.
+    StopCapture(String),
+    StopAllCaptures,
 }
 
-pub fn control_loop(cmd: Receiver<Command>, output: Sender<HttpStream>) {
-    let mut command_sender = None;
+pub fn control_loop(cmd: Receiver<Command>, output: Sender<Event>) {
+    // Command senders of the running captures by the name of their source
+    let mut captures: HashMap<String, Sender<capture::Command>> = HashMap::new();
 
     while let Ok(command) = cmd.recv() {
         info!("Command arrived: {command:?}");
 
         match command {
-            Command::StartCapture(interface) => {
-                command_sender = Some(start_capture(interface, output.clone()));
+            Command::StartCapture(source, config, id) => {
+                let name = source.to_string();
+
+                // Restarting a capture on the same interface replaces the old one
+                if let Some(tx) = captures.remove(&name) {
+                    stop_capture(&name, &tx);
+                }
+
+                captures.insert(name, start_capture(source, config, id, output.clone()));
             }
-            Command::StopCapture => {
-                if let Some(ref tx) = command_sender {
-                    tx.send(capture::Command::StopCapture).unwrap();
+            Command::StopCapture(name) => {
+                if let Some(tx) = captures.remove(&name) {
+                    stop_capture(&name, &tx);
+                }
+            }
+            Command::StopAllCaptures => {
+                for (name, tx) in captures.drain() {
+                    stop_capture(&name, &tx);
                 }
             }
         }
     }
 }
 
-pub fn start_capture(interface: String, packet_tx: Sender<HttpStream>) -> Sender<capture::Command> {
+pub fn start_capture(
+    source: CaptureSource,
+    config: CaptureConfig,
+    id: usize,
+    packet_tx: Sender<Event>,
+) -> Sender<capture::Command> {
     let (command_tx, command_rx) = channel::bounded(5);
-    let port = 80;
 
-    info!("Capturing on port {}", port);
+    info!("Capturing on ports {:?}", config.ports);
 
-    capture::start_capture(interface, port, packet_tx, command_rx);
+    capture::start_capture(source, config, id, packet_tx, command_rx);
 
     command_tx
 }
+
+fn stop_capture(name: &str, tx: &Sender<capture::Command>) {
+    // The capture may have already finished (end of a capture file)
+    if let Err(e) = tx.send(capture::Command::StopCapture) {
+        error!("Error stopping capture on {name}: {e:?}");
+    }
+}
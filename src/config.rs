@@ -0,0 +1,291 @@
+// Capture configuration which is kept between runs
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use log::{error, info};
+
+/// A single port or an inclusive range of ports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    fn to_bpf(self) -> String {
+        if self.start == self.end {
+            format!("port {}", self.start)
+        } else {
+            format!("portrange {}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |p: &str| {
+            u16::from_str(p.trim()).map_err(|_| format!("Invalid port number: {}", p.trim()))
+        };
+
+        let range = match s.split_once('-') {
+            Some((start, end)) => PortRange {
+                start: parse_port(start)?,
+                end: parse_port(end)?,
+            },
+            None => {
+                let port = parse_port(s)?;
+
+                PortRange {
+                    start: port,
+                    end: port,
+                }
+            }
+        };
+
+        if range.start > range.end {
+            return Err(format!("Invalid port range: {}", s.trim()));
+        }
+
+        Ok(range)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// Parse a comma separated list of ports and port ranges like `80, 8080, 3000-3010`.
+pub fn parse_ports(s: &str) -> Result<Vec<PortRange>, String> {
+    let ports = s
+        .split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(PortRange::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if ports.is_empty() {
+        return Err("At least one port is needed".to_string());
+    }
+
+    Ok(ports)
+}
+
+pub fn format_ports(ports: &[PortRange]) -> String {
+    ports
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parse the idle timeout given in seconds.
+pub fn parse_idle_timeout(s: &str) -> Result<Duration, String> {
+    match u64::from_str(s.trim()) {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("Invalid idle timeout: {}", s.trim())),
+    }
+}
+
+pub fn parse_max_streams(s: &str) -> Result<usize, String> {
+    match usize::from_str(s.trim()) {
+        Ok(max) if max > 0 => Ok(max),
+        _ => Err(format!("Invalid maximum number of streams: {}", s.trim())),
+    }
+}
+
+/// The settings of a capture which are sent with the start capture command.
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    pub ports: Vec<PortRange>,
+    /// User supplied BPF filter expression, it is used instead of the port filter.
+    pub filter: Option<String>,
+    /// Connections without packets for this long are evicted from the flow table.
+    pub idle_timeout: Duration,
+    /// The maximum number of connections tracked at the same time per capture.
+    pub max_streams: usize,
+    /// The NSS key log file (SSLKEYLOGFILE) with the secrets of the TLS connections.
+    pub keylog_file: Option<PathBuf>,
+    /// The FileDescriptorSet with the protobuf message types of the gRPC calls.
+    pub descriptor_set: Option<PathBuf>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            ports: vec![PortRange { start: 80, end: 80 }],
+            filter: None,
+            idle_timeout: Duration::from_secs(120),
+            max_streams: 10_000,
+            keylog_file: None,
+            descriptor_set: None,
+        }
+    }
+}
+
+/// Settings given on the command line. They are used instead of the saved ones in this run
+/// only, so they are never written to the config file.
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub ports: Option<Vec<PortRange>>,
+    pub filter: Option<String>,
+    pub idle_timeout: Option<Duration>,
+    pub max_streams: Option<usize>,
+    /// The key log file of the command line or of the SSLKEYLOGFILE environment variable.
+    pub keylog_file: Option<PathBuf>,
+    pub descriptor_set: Option<PathBuf>,
+}
+
+impl Overrides {
+    /// The config of the captures: the saved one with the overrides applied.
+    pub fn apply(&self, config: &CaptureConfig) -> CaptureConfig {
+        let mut config = config.clone();
+
+        if let Some(ref ports) = self.ports {
+            config.ports = ports.clone();
+        }
+
+        if let Some(ref filter) = self.filter {
+            config.filter = Some(filter.clone());
+        }
+
+        if let Some(timeout) = self.idle_timeout {
+            config.idle_timeout = timeout;
+        }
+
+        if let Some(max) = self.max_streams {
+            config.max_streams = max;
+        }
+
+        if let Some(ref file) = self.keylog_file {
+            config.keylog_file = Some(file.clone());
+        }
+
+        if let Some(ref file) = self.descriptor_set {
+            config.descriptor_set = Some(file.clone());
+        }
+
+        config
+    }
+}
+
+impl CaptureConfig {
+    /// The BPF filter expression of the capture. If there is no user defined filter, it lets
+    /// only the tcp packets of the ports through.
+    pub fn bpf_filter(&self) -> String {
+        if let Some(ref filter) = self.filter {
+            return filter.clone();
+        }
+
+        let ports = self
+            .ports
+            .iter()
+            .map(|p| p.to_bpf())
+            .collect::<Vec<_>>()
+            .join(" or ");
+
+        format!("tcp and ({ports})")
+    }
+
+    /// Load the configuration saved by the previous run. If there is no such, or it cannot
+    /// be read, the default config is used.
+    pub fn load() -> CaptureConfig {
+        let mut config = CaptureConfig::default();
+
+        let Some(path) = config_path() else {
+            return config;
+        };
+
+        let Ok(content) = fs::read_to_string(&path) else {
+            return config;
+        };
+
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            match key.trim() {
+                "ports" => match parse_ports(value) {
+                    Ok(ports) => config.ports = ports,
+                    Err(e) => error!("Error in {}: {e}", path.display()),
+                },
+                "filter" if !value.trim().is_empty() => {
+                    config.filter = Some(value.trim().to_string());
+                }
+                "idle_timeout" => match parse_idle_timeout(value) {
+                    Ok(timeout) => config.idle_timeout = timeout,
+                    Err(e) => error!("Error in {}: {e}", path.display()),
+                },
+                "max_streams" => match parse_max_streams(value) {
+                    Ok(max) => config.max_streams = max,
+                    Err(e) => error!("Error in {}: {e}", path.display()),
+                },
+                "keylog_file" if !value.trim().is_empty() => {
+                    config.keylog_file = Some(PathBuf::from(value.trim()));
+                }
+                "descriptor_set" if !value.trim().is_empty() => {
+                    config.descriptor_set = Some(PathBuf::from(value.trim()));
+                }
+                _ => {}
+            }
+        }
+
+        info!("Config loaded from {}", path.display());
+
+        config
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = config_path().ok_or(io::Error::other("Cannot find home directory"))?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut f = fs::File::create(path)?;
+
+        writeln!(f, "ports = {}", format_ports(&self.ports))?;
+        writeln!(f, "idle_timeout = {}", self.idle_timeout.as_secs())?;
+        writeln!(f, "max_streams = {}", self.max_streams)?;
+
+        if let Some(ref filter) = self.filter {
+            writeln!(f, "filter = {}", filter)?;
+        }
+
+        if let Some(ref keylog_file) = self.keylog_file {
+            writeln!(f, "keylog_file = {}", keylog_file.display())?;
+        }
+
+        if let Some(ref descriptor_set) = self.descriptor_set {
+            writeln!(f, "descriptor_set = {}", descriptor_set.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("http-capture").join("config"))
+}
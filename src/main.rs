@@ -8,19 +8,62 @@ use log::{info, Level, LevelFilter, Log};
 use std::{
     fs::File,
     io::{stdout, Result, Write},
+    path::PathBuf,
+    process,
     sync::Mutex,
     thread,
 };
 
+use capture::CaptureSource;
+use config::{CaptureConfig, Overrides};
+use savefile::SaveFormat;
+
 mod capture;
 mod capture_control;
+mod config;
+mod savefile;
 mod stream;
 pub mod ui;
 
+const USAGE: &str = r#"Usage: http-capture [OPTIONS]
+
+Options:
+  -r, --read <FILE>              Read packets from a pcap/pcapng file
+  -p, --ports <PORTS>            Capture on these ports, e.g. 80,8080,3000-3010
+  -f, --filter <EXPR>            BPF filter expression used instead of the ports
+      --save-format <FORMAT>     Format of the saved packets: pcap (default) or pcapng
+      --idle-timeout <SECS>      Evict connections idle for this long (default 120)
+      --max-streams <N>          Maximum number of tracked connections (default 10000)
+      --keylog-file <FILE>       Decrypt TLS with the secrets of this key log file
+                                 (default: $SSLKEYLOGFILE)
+      --descriptor-set <FILE>    Decode gRPC messages with the types of this
+                                 FileDescriptorSet (protoc --descriptor_set_out)
+  -h, --help                     Print help
+"#;
+
 struct FileLog {
     file: Mutex<File>,
 }
 
+/// Command line arguments.
+#[derive(Default)]
+struct Args {
+    /// Capture file to be read at start up instead of choosing a device.
+    read_file: Option<PathBuf>,
+    /// Ports overriding the ones in the saved config.
+    ports: Option<String>,
+    /// BPF filter overriding the one in the saved config.
+    filter: Option<String>,
+    /// The file format the packets of the streams are saved in.
+    save_format: Option<SaveFormat>,
+    idle_timeout: Option<String>,
+    max_streams: Option<String>,
+    /// Key log file overriding the one in the saved config.
+    keylog_file: Option<PathBuf>,
+    /// Descriptor set overriding the one in the saved config.
+    descriptor_set: Option<PathBuf>,
+}
+
 // TODO:
 //  - break long lines in http info window
 //  - add sequence number to stream list
@@ -28,6 +71,64 @@ struct FileLog {
 //  - dump request-response to text file
 
 fn main() -> Result<()> {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            process::exit(1);
+        }
+    };
+
+    let config = CaptureConfig::load();
+    let mut overrides = Overrides::default();
+
+    if let Some(ref ports) = args.ports {
+        match config::parse_ports(ports) {
+            Ok(ports) => overrides.ports = Some(ports),
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(filter) = args.filter {
+        if let Err(e) = capture::check_filter(&filter, &[]) {
+            eprintln!("Invalid filter: {e}");
+            process::exit(1);
+        }
+
+        overrides.filter = Some(filter);
+    }
+
+    if let Some(ref timeout) = args.idle_timeout {
+        match config::parse_idle_timeout(timeout) {
+            Ok(timeout) => overrides.idle_timeout = Some(timeout),
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(ref max) = args.max_streams {
+        match config::parse_max_streams(max) {
+            Ok(max) => overrides.max_streams = Some(max),
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(file) = args.keylog_file {
+        overrides.keylog_file = Some(file);
+    } else if config.keylog_file.is_none() {
+        overrides.keylog_file = std::env::var_os("SSLKEYLOGFILE").map(PathBuf::from);
+    }
+
+    overrides.descriptor_set = args.descriptor_set;
+
     setup_logger();
 
     let mut terminal = ratatui::init();
@@ -37,12 +138,20 @@ fn main() -> Result<()> {
     let (http_tx, http_rx) = channel::bounded(32);
     let (cmd_tx, cmd_rx) = channel::bounded(4);
 
-    let state = ui::State::new(http_rx, cmd_tx);
+    let mut state = ui::State::new(http_rx, cmd_tx, config, overrides);
 
-    let capture_handle = thread::spawn(move || {
+    let _capture_handle = thread::spawn(move || {
         capture_control::control_loop(cmd_rx, http_tx);
     });
 
+    if let Some(format) = args.save_format {
+        state.set_save_format(format);
+    }
+
+    if let Some(file) = args.read_file {
+        state.start_capture(CaptureSource::File(file));
+    }
+
     info!("Starting main ui");
 
     let result = ui::run_app(&mut terminal, state);
@@ -59,6 +168,62 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn parse_args() -> std::result::Result<Args, String> {
+    let mut args = Args::default();
+    let mut argv = std::env::args().skip(1);
+
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-r" | "--read" => {
+                let file = argv
+                    .next()
+                    .ok_or(format!("Missing file name after {arg}"))?;
+
+                args.read_file = Some(PathBuf::from(file));
+            }
+            "-p" | "--ports" => {
+                args.ports = Some(argv.next().ok_or(format!("Missing ports after {arg}"))?);
+            }
+            "-f" | "--filter" => {
+                args.filter = Some(argv.next().ok_or(format!("Missing filter after {arg}"))?);
+            }
+            "--save-format" => {
+                let format = argv.next().ok_or(format!("Missing format after {arg}"))?;
+
+                args.save_format = Some(format.parse()?);
+            }
+            "--idle-timeout" => {
+                args.idle_timeout =
+                    Some(argv.next().ok_or(format!("Missing seconds after {arg}"))?);
+            }
+            "--max-streams" => {
+                args.max_streams = Some(argv.next().ok_or(format!("Missing number after {arg}"))?);
+            }
+            "--keylog-file" => {
+                let file = argv
+                    .next()
+                    .ok_or(format!("Missing file name after {arg}"))?;
+
+                args.keylog_file = Some(PathBuf::from(file));
+            }
+            "--descriptor-set" => {
+                let file = argv
+                    .next()
+                    .ok_or(format!("Missing file name after {arg}"))?;
+
+                args.descriptor_set = Some(PathBuf::from(file));
+            }
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument {arg}")),
+        }
+    }
+
+    Ok(args)
+}
+
 impl Log for FileLog {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
         metadata.level() <= Level::Info
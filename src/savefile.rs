@@ -0,0 +1,174 @@
+// Saving the raw packets of the captured streams to pcap or pcapng files
+
+use std::{borrow::Cow, fs::File, io::BufWriter, path::Path, str::FromStr, time::Duration};
+
+use pcap::Linktype;
+use pcap_file::{
+    pcap::{PcapHeader, PcapPacket, PcapWriter},
+    pcapng::{
+        blocks::{
+            enhanced_packet::EnhancedPacketBlock,
+            interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption},
+        },
+        PcapNgWriter,
+    },
+    DataLink, TsResolution,
+};
+
+const SNAPLEN: u32 = 262144;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SaveFormat {
+    Pcap,
+    PcapNg,
+}
+
+impl SaveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SaveFormat::Pcap => "pcap",
+            SaveFormat::PcapNg => "pcapng",
+        }
+    }
+}
+
+impl FromStr for SaveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pcap" => Ok(SaveFormat::Pcap),
+            "pcapng" => Ok(SaveFormat::PcapNg),
+            _ => Err(format!("Unknown save format {s}, use pcap or pcapng")),
+        }
+    }
+}
+
+/// The LINKTYPE number of the capture files for the DLT value libpcap gives for a capture.
+/// They are the same, except the ones which differ between platforms, like raw IP.
+pub fn file_linktype(datalink: Linktype) -> Linktype {
+    match datalink.0 {
+        // OpenBSD has DLT_LOOP as 12 and DLT_RAW as 14, the others DLT_RAW as 12
+        12 if cfg!(target_os = "openbsd") => Linktype::LOOP,
+        12 => Linktype::RAW,
+        14 if cfg!(target_os = "openbsd") => Linktype::RAW,
+        _ => datalink,
+    }
+}
+
+/// A packet as it was captured, link layer header included.
+#[derive(Clone)]
+pub struct RawPacket {
+    /// The LINKTYPE number of the packet, not the DLT value of libpcap.
+    pub linktype: Linktype,
+    pub timestamp: Duration,
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+impl RawPacket {
+    pub fn new(linktype: Linktype, packet: &pcap::Packet) -> RawPacket {
+        let ts = packet.header.ts;
+
+        RawPacket {
+            linktype,
+            timestamp: Duration::new(ts.tv_sec as u64, ts.tv_usec as u32 * 1000),
+            orig_len: packet.header.len,
+            data: packet.data.to_vec(),
+        }
+    }
+}
+
+impl std::fmt::Debug for RawPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawPacket")
+            .field("timestamp", &self.timestamp)
+            .field("data", &self.data.len())
+            .finish()
+    }
+}
+
+/// Write the packets to `path` in the given format. The packets need to be in the order
+/// they should appear in the file.
+pub fn write_packets(
+    path: &Path,
+    format: SaveFormat,
+    packets: &[&RawPacket],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let writer = BufWriter::new(File::create(path)?);
+
+    match format {
+        SaveFormat::Pcap => write_pcap(writer, packets),
+        SaveFormat::PcapNg => write_pcapng(writer, packets),
+    }
+}
+
+fn write_pcap(
+    writer: BufWriter<File>,
+    packets: &[&RawPacket],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // A pcap file has only one link type in its header
+    let linktype = packets
+        .first()
+        .map(|p| p.linktype)
+        .unwrap_or(Linktype::ETHERNET);
+
+    if packets.iter().any(|p| p.linktype != linktype) {
+        return Err("Packets have different link types, save them as pcapng".into());
+    }
+
+    let header = PcapHeader {
+        snaplen: SNAPLEN,
+        datalink: DataLink::from(linktype.0 as u32),
+        ts_resolution: TsResolution::MicroSecond,
+        ..Default::default()
+    };
+
+    let mut pcap_writer = PcapWriter::with_header(writer, header)?;
+
+    for packet in packets {
+        pcap_writer.write_packet(&PcapPacket::new(
+            packet.timestamp,
+            packet.orig_len,
+            &packet.data,
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn write_pcapng(
+    writer: BufWriter<File>,
+    packets: &[&RawPacket],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut pcapng_writer = PcapNgWriter::new(writer)?;
+    let mut interfaces: Vec<Linktype> = vec![];
+
+    for packet in packets {
+        // Every link type gets its own interface description
+        let interface_id = match interfaces.iter().position(|l| *l == packet.linktype) {
+            Some(i) => i,
+            None => {
+                pcapng_writer.write_pcapng_block(InterfaceDescriptionBlock {
+                    linktype: DataLink::from(packet.linktype.0 as u32),
+                    snaplen: SNAPLEN,
+                    // Timestamps are written in nanoseconds
+                    options: vec![InterfaceDescriptionOption::IfTsResol(9)],
+                })?;
+
+                interfaces.push(packet.linktype);
+                interfaces.len() - 1
+            }
+        };
+
+        pcapng_writer.write_pcapng_block(EnhancedPacketBlock {
+            interface_id: interface_id as u32,
+            timestamp: packet.timestamp,
+            original_len: packet.orig_len,
+            data: Cow::Borrowed(&packet.data),
+            options: vec![],
+        })?;
+    }
+
+    Ok(())
+}
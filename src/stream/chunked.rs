@@ -0,0 +1,259 @@
+// Decoding bodies sent with chunked transfer coding (RFC 9112 section 7.1)
+
+/// The decoded chunks of a message body.
+#[derive(Debug, Default)]
+pub struct ChunkedBody {
+    pub data: Vec<u8>,
+    /// Trailer fields sent after the last chunk.
+    pub trailers: Vec<(String, String)>,
+    /// The number of bytes the chunked body took in the message.
+    pub len: usize,
+    /// The last chunk and the trailer section have been received.
+    pub complete: bool,
+}
+
+/// Decode the chunks from the beginning of `bytes`. Chunk extensions are ignored. If the
+/// body is not complete, the chunks received so far are given back, and `len` is the
+/// length of the bytes.
+pub fn decode(bytes: &[u8]) -> Result<ChunkedBody, String> {
+    parse(bytes, true)
+}
+
+/// Find the end of the chunked body without copying the chunk data, so that a partial
+/// body received in many packets is not decoded again with each of them.
+pub fn scan(bytes: &[u8]) -> Result<ChunkedBody, String> {
+    parse(bytes, false)
+}
+
+fn parse(bytes: &[u8], copy_data: bool) -> Result<ChunkedBody, String> {
+    let mut body = ChunkedBody::default();
+    let mut pos = 0;
+
+    loop {
+        let Some(line) = next_line(&bytes[pos..]) else {
+            body.len = bytes.len();
+
+            return Ok(body);
+        };
+
+        let size = chunk_size(&bytes[pos..pos + line.content])?;
+
+        pos += line.len;
+
+        if size == 0 {
+            return read_trailers(bytes, pos, body);
+        }
+
+        let available = bytes.len() - pos;
+
+        if available < size {
+            if copy_data {
+                body.data.extend_from_slice(&bytes[pos..]);
+            }
+
+            body.len = bytes.len();
+
+            return Ok(body);
+        }
+
+        if copy_data {
+            body.data.extend_from_slice(&bytes[pos..pos + size]);
+        }
+
+        pos += size;
+
+        // The chunk data is closed by a line break
+        match next_line(&bytes[pos..]) {
+            Some(line) if line.content == 0 => pos += line.len,
+            Some(_) => return Err("Missing line break after chunk data".to_string()),
+            None => {
+                body.len = bytes.len();
+
+                return Ok(body);
+            }
+        }
+    }
+}
+
+/// Decodes a chunked body while it is being received, the bytes are decoded only once.
+/// The trailer section is not parsed.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// The position in the chunked body up to which it is decoded.
+    pos: usize,
+    /// The bytes of the current chunk which are not received yet.
+    chunk_left: usize,
+    /// The line break after the chunk data is expected.
+    data_end: bool,
+    /// The last chunk has been received.
+    done: bool,
+}
+
+impl Decoder {
+    /// Decode the bytes added to the chunked body since the previous call, `bytes` is the
+    /// body received so far. The chunk data is appended to `data`.
+    pub fn decode(&mut self, bytes: &[u8], data: &mut Vec<u8>) -> Result<(), String> {
+        while !self.done && self.pos < bytes.len() {
+            if self.chunk_left > 0 {
+                let len = self.chunk_left.min(bytes.len() - self.pos);
+
+                data.extend_from_slice(&bytes[self.pos..self.pos + len]);
+                self.pos += len;
+                self.chunk_left -= len;
+
+                continue;
+            }
+
+            let Some(line) = next_line(&bytes[self.pos..]) else {
+                break;
+            };
+
+            if self.data_end {
+                if line.content > 0 {
+                    return Err("Missing line break after chunk data".to_string());
+                }
+
+                self.data_end = false;
+            } else {
+                match chunk_size(&bytes[self.pos..self.pos + line.content])? {
+                    0 => self.done = true,
+                    size => {
+                        self.chunk_left = size;
+                        self.data_end = true;
+                    }
+                }
+            }
+
+            self.pos += line.len;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the size of the chunk from its first line. Extensions follow the size after a
+/// semicolon.
+fn chunk_size(line: &[u8]) -> Result<usize, String> {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or("").trim();
+
+    usize::from_str_radix(size, 16).map_err(|_| format!("Invalid chunk size: {}", line.trim()))
+}
+
+fn read_trailers(
+    bytes: &[u8],
+    mut pos: usize,
+    mut body: ChunkedBody,
+) -> Result<ChunkedBody, String> {
+    loop {
+        let Some(line) = next_line(&bytes[pos..]) else {
+            body.len = bytes.len();
+
+            return Ok(body);
+        };
+
+        let field = &bytes[pos..pos + line.content];
+
+        pos += line.len;
+
+        if field.is_empty() {
+            body.len = pos;
+            body.complete = true;
+
+            return Ok(body);
+        }
+
+        let field = String::from_utf8_lossy(field);
+        let (name, value) = field
+            .split_once(':')
+            .ok_or(format!("Invalid trailer field: {field}"))?;
+
+        body.trailers
+            .push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+struct LineEnd {
+    /// The length of the line without the line break.
+    content: usize,
+    /// The length with the line break.
+    len: usize,
+}
+
+/// Find the end of the next line, the line break can be CRLF or a bare LF.
+fn next_line(bytes: &[u8]) -> Option<LineEnd> {
+    let lf = bytes.iter().position(|b| *b == b'\n')?;
+    let content = if lf > 0 && bytes[lf - 1] == b'\r' {
+        lf - 1
+    } else {
+        lf
+    };
+
+    Some(LineEnd {
+        content,
+        len: lf + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] =
+        b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\nNEXT";
+
+    #[test]
+    fn chunks_and_trailers() {
+        let body = decode(BODY).unwrap();
+
+        assert_eq!(body.data, b"hello, world");
+        assert_eq!(
+            body.trailers,
+            [
+                ("Expires".to_string(), "never".to_string()),
+                ("X-Sum".to_string(), "1".to_string())
+            ]
+        );
+        assert_eq!(body.len, BODY.len() - 4);
+        assert!(body.complete);
+
+        let scanned = scan(BODY).unwrap();
+
+        assert!(scanned.data.is_empty());
+        assert_eq!((scanned.len, scanned.complete), (body.len, true));
+    }
+
+    #[test]
+    fn partial_body() {
+        for end in 0..BODY.len() - 6 {
+            let body = decode(&BODY[..end]).unwrap();
+
+            assert!(!body.complete, "complete at {end}");
+            assert_eq!(body.len, end);
+            assert!(b"hello, world".starts_with(&body.data));
+        }
+    }
+
+    #[test]
+    fn decoder_gets_the_bytes_one_by_one() {
+        let mut decoder = Decoder::default();
+        let mut data = vec![];
+
+        for end in 0..=BODY.len() {
+            decoder.decode(&BODY[..end], &mut data).unwrap();
+        }
+
+        assert_eq!(data, b"hello, world");
+        assert!(decoder.done);
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        assert!(decode(b"zz\r\nhello\r\n").is_err());
+        assert!(decode(b"5\r\nhello!\r\n").is_err());
+        assert!(decode(b"0\r\nno colon\r\n\r\n").is_err());
+        assert!(Decoder::default()
+            .decode(b"5\r\nhello!\r\n", &mut vec![])
+            .is_err());
+    }
+}
@@ -0,0 +1,55 @@
+// Decoding the content codings of message bodies (Content-Encoding header)
+
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+
+/// Bodies are not decoded beyond this size, a small compressed body can expand to gigabytes.
+pub const MAX_DECODED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Decode the body according to the value of the Content-Encoding header. Codings are
+/// listed in the order they were applied, so they are decoded in reverse order.
+pub fn decode(encodings: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut data = data.to_vec();
+
+    for coding in encodings.rsplit(',').map(str::trim) {
+        data = match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => data,
+            "gzip" | "x-gzip" => read_all(MultiGzDecoder::new(&data[..]), coding)?,
+            "deflate" => inflate(&data)?,
+            "br" => read_all(brotli::Decompressor::new(&data[..], 4096), coding)?,
+            "zstd" => {
+                let decoder = ruzstd::decoding::StreamingDecoder::new(&data[..])
+                    .map_err(|e| format!("Cannot decode zstd body: {e}"))?;
+
+                read_all(decoder, coding)?
+            }
+            _ => return Err(format!("Unknown encoding {coding}")),
+        };
+    }
+
+    Ok(data)
+}
+
+/// Deflate coding is zlib format, but some servers send raw deflate data.
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    read_all(ZlibDecoder::new(data), "deflate")
+        .or_else(|_| read_all(DeflateDecoder::new(data), "deflate"))
+}
+
+fn read_all(reader: impl Read, coding: &str) -> Result<Vec<u8>, String> {
+    let mut decoded = vec![];
+
+    reader
+        .take(MAX_DECODED_BYTES as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e| format!("Cannot decode {coding} body: {e}"))?;
+
+    if decoded.len() > MAX_DECODED_BYTES {
+        return Err(format!(
+            "Cannot decode {coding} body: decoded body is too large"
+        ));
+    }
+
+    Ok(decoded)
+}
@@ -0,0 +1,231 @@
+// Parsing form bodies: multipart/form-data (RFC 7578) and application/x-www-form-urlencoded
+
+use super::{declared_charset, header_params, max_headers, percent_decode, percent_decode_bytes};
+use crate::ui::stream::{Form, FormPart, Headers};
+
+/// Parse the body if it is a form according to its content type.
+pub fn parse(headers: &Headers, data: &[u8]) -> Option<Form> {
+    let (media_type, params) = header_params(headers.get("content-type")?);
+
+    if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return Some(Form::UrlEncoded(parse_urlencoded(data)));
+    }
+
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    let Some((_, boundary)) = params.into_iter().find(|(name, _)| name == "boundary") else {
+        return Some(Form::Multipart {
+            parts: vec![],
+            error: Some("The boundary is missing from Content-Type".to_string()),
+        });
+    };
+
+    let (parts, error) = parse_multipart(data, &boundary);
+
+    Some(Form::Multipart { parts, error })
+}
+
+/// Split the body at the boundary delimiters into parts. The parts parsed before an error
+/// are given back with the error.
+fn parse_multipart(data: &[u8], boundary: &str) -> (Vec<FormPart>, Option<String>) {
+    let delimiter = format!("--{boundary}");
+    let mut parts = vec![];
+
+    // The preamble before the first delimiter is ignored
+    let Some(mut pos) = find_delimiter(data, 0, delimiter.as_bytes()) else {
+        return (parts, Some("No boundary delimiter in the body".to_string()));
+    };
+
+    loop {
+        pos += delimiter.len();
+
+        if data[pos..].starts_with(b"--") {
+            // The closing delimiter, the epilogue after it is ignored
+            return (parts, None);
+        }
+
+        let Some(line_end) = data[pos..].iter().position(|b| *b == b'\n') else {
+            return (parts, Some("The body ends after a delimiter".to_string()));
+        };
+
+        let start = pos + line_end + 1;
+        let next = find_delimiter(data, start, delimiter.as_bytes());
+        // The line break before the delimiter belongs to the delimiter
+        let end = match next {
+            Some(next) if data[..next].ends_with(b"\r\n") => next - 2,
+            Some(next) => next - 1,
+            None => data.len(),
+        };
+
+        match parse_part(&data[start..end.max(start)]) {
+            Ok(part) => parts.push(part),
+            Err(e) => return (parts, Some(e)),
+        }
+
+        match next {
+            Some(next) => pos = next,
+            None => return (parts, Some("The closing delimiter is missing".to_string())),
+        }
+    }
+}
+
+/// Find the delimiter at the start of a line, from the position.
+fn find_delimiter(data: &[u8], from: usize, delimiter: &[u8]) -> Option<usize> {
+    (from..data.len())
+        .filter(|i| *i == 0 || data[i - 1] == b'\n')
+        .find(|i| data[*i..].starts_with(delimiter))
+}
+
+fn parse_part(data: &[u8]) -> Result<FormPart, String> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers(data)];
+
+    let (body_start, parsed) = match httparse::parse_headers(data, &mut headers) {
+        Ok(httparse::Status::Complete(parsed)) => parsed,
+        Ok(httparse::Status::Partial) => return Err("Partial headers of a part".to_string()),
+        Err(e) => return Err(format!("Invalid headers of a part: {e}")),
+    };
+
+    let mut part = FormPart {
+        data: data[body_start..].to_vec(),
+        ..Default::default()
+    };
+
+    for header in parsed {
+        part.headers.push(
+            header.name.to_string(),
+            String::from_utf8_lossy(header.value).to_string(),
+        );
+    }
+
+    part.charset = declared_charset(&part.headers);
+
+    if let Some(disposition) = part.headers.get("content-disposition") {
+        let (_, params) = header_params(disposition);
+
+        for (name, value) in params {
+            match name.as_str() {
+                "name" => part.name = Some(value),
+                "filename" if part.filename.is_none() => part.filename = Some(value),
+                // The extended parameter, like `UTF-8''na%C3%AFve.txt`, takes precedence
+                "filename*" => {
+                    let encoded = value.splitn(3, '\'').nth(2).unwrap_or(&value);
+
+                    part.filename = Some(percent_decode(encoded));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(part)
+}
+
+/// Split the `name=value` pairs, the escapes of the names and values are decoded. They are
+/// decoded to text with the charset of the body when they are shown.
+fn parse_urlencoded(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let decode = |s: &[u8]| {
+        let s: Vec<u8> = s
+            .iter()
+            .map(|b| if *b == b'+' { b' ' } else { *b })
+            .collect();
+
+        percent_decode_bytes(&s)
+    };
+    let end = data
+        .iter()
+        .rposition(|b| *b != b'\r' && *b != b'\n')
+        .map_or(0, |i| i + 1);
+
+    data[..end]
+        .split(|b| *b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.iter().position(|b| *b == b'=') {
+            Some(i) => (decode(&pair[..i]), decode(&pair[i + 1..])),
+            None => (decode(pair), vec![]),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPART: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Hello\r\n--not the boundary\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"; \
+        filename*=UTF-8''na%C3%AFve.txt\r\n\
+        Content-Type: text/plain; charset=latin1\r\n\r\n\
+        data\n--XyZ--\r\nepilogue";
+
+    fn form(content_type: &str, data: &[u8]) -> Option<Form> {
+        let mut headers = Headers::default();
+
+        headers.push("Content-Type".to_string(), content_type.to_string());
+
+        parse(&headers, data)
+    }
+
+    #[test]
+    fn multipart() {
+        let Some(Form::Multipart { parts, error }) =
+            form("multipart/form-data; boundary=\"XyZ\"", MULTIPART)
+        else {
+            panic!("Not a multipart form");
+        };
+
+        assert_eq!(error, None);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].data, b"Hello\r\n--not the boundary");
+        assert_eq!(parts[1].name.as_deref(), Some("file"));
+        assert_eq!(parts[1].filename.as_deref(), Some("naïve.txt"));
+        assert_eq!(parts[1].charset.as_deref(), Some("latin1"));
+        assert_eq!(parts[1].data, b"data");
+    }
+
+    #[test]
+    fn truncated_multipart() {
+        let body = &MULTIPART[..MULTIPART.len() - 20];
+        let Some(Form::Multipart { parts, error }) =
+            form("multipart/form-data; boundary=XyZ", body)
+        else {
+            panic!("Not a multipart form");
+        };
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(error.as_deref(), Some("The closing delimiter is missing"));
+
+        let Some(Form::Multipart { parts, error }) = form("multipart/form-data", MULTIPART) else {
+            panic!("Not a multipart form");
+        };
+
+        assert!(parts.is_empty());
+        assert_eq!(
+            error.as_deref(),
+            Some("The boundary is missing from Content-Type")
+        );
+    }
+
+    #[test]
+    fn urlencoded() {
+        let Some(Form::UrlEncoded(fields)) = form(
+            "application/x-www-form-urlencoded",
+            b"q=a+b%26c&&empty=&flag\r\n",
+        ) else {
+            panic!("Not an urlencoded form");
+        };
+
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (b"q".to_vec(), b"a b&c".to_vec()),
+            (b"empty".to_vec(), vec![]),
+            (b"flag".to_vec(), vec![]),
+        ];
+
+        assert_eq!(fields, expected);
+        assert!(form("application/json", b"{}").is_none());
+    }
+}
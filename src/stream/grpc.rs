@@ -0,0 +1,163 @@
+// Splitting the bodies of gRPC calls into messages (gRPC over HTTP/2 protocol)
+
+use super::{
+    encoding, percent_decode,
+    protobuf::{self, Schema},
+};
+use crate::ui::stream::{Body, GrpcMessage, GrpcStatus, Headers, HttpStream, Resp};
+
+/// The messages are prefixed with a compressed flag and their 4 bytes length.
+const PREFIX_LEN: usize = 5;
+
+/// Decode the messages and the status of the exchange, if it is a gRPC call. The messages
+/// are decoded to JSON with the request and response types of the method in the schema,
+/// otherwise they are shown in the protobuf wire format.
+pub fn decode(stream: &mut HttpStream, schema: Option<&Schema>) {
+    let Some(ref request) = stream.parsed_request else {
+        return;
+    };
+    let response = stream.parsed_response.as_ref();
+
+    if !is_grpc(&request.headers) && !response.is_some_and(|resp| is_grpc(&resp.headers)) {
+        return;
+    }
+
+    let method = schema.and_then(|schema| Some((schema, schema.method(&request.path)?)));
+    let request_type = method.map(|(schema, (input, _))| (schema, input));
+    let response_type = method.map(|(schema, (_, output))| (schema, output));
+
+    let mut messages = split(true, &request.headers, request.body.as_ref(), request_type);
+
+    if let Some(response) = response {
+        messages.extend(split(
+            false,
+            &response.headers,
+            response.body.as_ref(),
+            response_type,
+        ));
+
+        stream.grpc_status = status(response);
+    }
+
+    stream.grpc_messages = messages;
+}
+
+/// Checks if the content type is gRPC, like `application/grpc` or `application/grpc+proto`.
+/// gRPC-Web is framed differently.
+fn is_grpc(headers: &Headers) -> bool {
+    headers.get("content-type").is_some_and(|content_type| {
+        let content_type = content_type.trim().to_ascii_lowercase();
+
+        content_type == "application/grpc"
+            || content_type.starts_with("application/grpc+")
+            || content_type.starts_with("application/grpc;")
+    })
+}
+
+/// Split the body into the length-prefixed messages, and decode them with the message
+/// type if it is known.
+fn split(
+    from_client: bool,
+    headers: &Headers,
+    body: Option<&Body>,
+    message_type: Option<(&Schema, &str)>,
+) -> Vec<GrpcMessage> {
+    let Some(body) = body else {
+        return vec![];
+    };
+
+    let encoding = headers
+        .get("grpc-encoding")
+        .map(str::trim)
+        .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+    let mut data = &body.data[..];
+    let mut messages = vec![];
+
+    while !data.is_empty() {
+        let len = data
+            .get(1..PREFIX_LEN)
+            .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize);
+
+        let Some(len) = len.filter(|len| PREFIX_LEN + len <= data.len()) else {
+            messages.push(GrpcMessage {
+                from_client,
+                data: data.to_vec(),
+                error: Some("Message is not complete".to_string()),
+                ..Default::default()
+            });
+
+            break;
+        };
+
+        let mut message = GrpcMessage {
+            from_client,
+            compressed: data[0] & 0x01 != 0,
+            data: data[PREFIX_LEN..PREFIX_LEN + len].to_vec(),
+            ..Default::default()
+        };
+
+        data = &data[PREFIX_LEN + len..];
+
+        if message.compressed {
+            let decompressed = match encoding {
+                Some(encoding) => encoding::decode(encoding, &message.data),
+                None => Err("Compressed message without grpc-encoding".to_string()),
+            };
+
+            match decompressed {
+                Ok(decompressed) => message.data = decompressed,
+                Err(e) => {
+                    message.error = Some(e);
+                    messages.push(message);
+
+                    continue;
+                }
+            }
+        }
+
+        decode_message(&mut message, message_type);
+        messages.push(message);
+    }
+
+    messages
+}
+
+fn decode_message(message: &mut GrpcMessage, message_type: Option<(&Schema, &str)>) {
+    if let Some((schema, message_type)) = message_type {
+        match schema.to_json(message_type, &message.data) {
+            Ok(json) => {
+                message.message_type = Some(message_type.to_string());
+                message.decoded = Some(json);
+
+                return;
+            }
+            Err(e) => message.error = Some(format!("Cannot decode as {message_type}: {e}")),
+        }
+    }
+
+    match protobuf::decode_raw(&message.data) {
+        Ok(dump) => message.decoded = Some(dump),
+        Err(e) => {
+            message
+                .error
+                .get_or_insert(format!("Not a protobuf message: {e}"));
+        }
+    }
+}
+
+/// The status of the call is in the trailers, or in the headers of a response without
+/// messages.
+fn status(response: &Resp) -> Option<GrpcStatus> {
+    let field = |name| {
+        response
+            .trailers
+            .get(name)
+            .or_else(|| response.headers.get(name))
+    };
+
+    Some(GrpcStatus {
+        code: field("grpc-status")?.trim().parse().ok()?,
+        // The status message is percent-encoded UTF-8
+        message: field("grpc-message").map(percent_decode),
+    })
+}
@@ -0,0 +1,753 @@
+// Decoding HTTP/2 cleartext (h2c) connections (RFC 9113)
+
+use std::collections::BTreeMap;
+
+use bytes::{Buf, BytesMut};
+use loona_hpack::Decoder;
+
+use super::{decode_body, reassembly::ByteStream, ParseError, PendingExchange};
+use crate::ui::stream::{Headers, Req, Resp};
+
+/// The client starts the connection with this preface.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const GOAWAY: u8 = 0x7;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: BytesMut,
+}
+
+impl Frame {
+    fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Remove the padding of DATA, HEADERS and PUSH_PROMISE frames.
+    fn unpad(&mut self) -> Result<(), String> {
+        if !self.has_flag(PADDED) {
+            return Ok(());
+        }
+
+        if self.payload.is_empty() {
+            return Err("Missing pad length".to_string());
+        }
+
+        let pad_len = self.payload.get_u8() as usize;
+
+        if pad_len > self.payload.len() {
+            return Err("Padding exceeds the frame".to_string());
+        }
+
+        self.payload.truncate(self.payload.len() - pad_len);
+
+        Ok(())
+    }
+}
+
+/// A header block which is continued in CONTINUATION frames.
+struct HeaderBlock {
+    stream_id: u32,
+    /// The stream promised by a PUSH_PROMISE frame.
+    promised_id: Option<u32>,
+    end_stream: bool,
+    fragment: Vec<u8>,
+}
+
+#[derive(Default)]
+struct H2Stream {
+    /// The HTTP/1.1 request which was upgraded to HTTP/2, it is stream 1.
+    upgraded_request: Option<Req>,
+    request_headers: Option<Headers>,
+    request_trailers: Headers,
+    request_body: Vec<u8>,
+    request_ended: bool,
+    interim_responses: Vec<Resp>,
+    response_headers: Option<Headers>,
+    response_trailers: Headers,
+    response_body: Vec<u8>,
+    response_ended: bool,
+    events: Vec<String>,
+    error: Option<String>,
+}
+
+impl H2Stream {
+    fn is_finished(&self) -> bool {
+        (self.request_ended && self.response_ended) || self.error.is_some()
+    }
+
+    fn into_exchange(self) -> (PendingExchange, Option<Resp>) {
+        let request = match self.upgraded_request {
+            Some(request) => Some(request),
+            None => self
+                .request_headers
+                .map(|headers| make_request(headers, self.request_trailers, self.request_body)),
+        };
+
+        let response = self
+            .response_headers
+            .map(|headers| make_response(headers, self.response_trailers, self.response_body));
+
+        let mut pending = PendingExchange::new(request, 0);
+
+        pending.interim_responses = self.interim_responses;
+        pending.events = self.events;
+        pending.error = self.error;
+
+        if response.is_none() && pending.error.is_none() {
+            pending.error = Some("No response".to_string());
+        }
+
+        (pending, response)
+    }
+}
+
+/// The state of an HTTP/2 connection: the HPACK decoders of the two directions and the
+/// streams which are not finished yet.
+pub struct Connection {
+    client_decoder: Decoder<'static>,
+    server_decoder: Decoder<'static>,
+    /// The client preface has been received.
+    preface_received: bool,
+    client_block: Option<HeaderBlock>,
+    server_block: Option<HeaderBlock>,
+    streams: BTreeMap<u32, H2Stream>,
+    /// The highest id of the taken streams initiated by the server (even) and by the client
+    /// (odd). The frames arriving later on these streams are ignored.
+    closed_ids: [u32; 2],
+    /// The connection cannot be decoded further, because of missing bytes or errors.
+    broken: Option<String>,
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Connection {
+            client_decoder: Decoder::new(),
+            server_decoder: Decoder::new(),
+            preface_received: false,
+            client_block: None,
+            server_block: None,
+            streams: BTreeMap::new(),
+            closed_ids: [0, 0],
+            broken: None,
+        }
+    }
+
+    /// The connection is upgraded from HTTP/1.1 with `Upgrade: h2c`. The request becomes
+    /// stream 1, and its response comes in HTTP/2 frames.
+    pub fn upgraded(request: Req, interim_responses: Vec<Resp>) -> Self {
+        let mut connection = Connection::new();
+
+        connection.streams.insert(
+            1,
+            H2Stream {
+                upgraded_request: Some(request),
+                request_ended: true,
+                interim_responses,
+                ..Default::default()
+            },
+        );
+
+        connection
+    }
+
+    /// Decode the frames received from the client or from the server.
+    pub fn receive(&mut self, from_client: bool, bytes: &mut ByteStream) {
+        if self.broken.is_some() {
+            bytes.discard();
+
+            return;
+        }
+
+        if from_client && !self.preface_received {
+            if bytes.peek().len() < PREFACE.len() && !bytes.is_closed() {
+                return;
+            }
+
+            if !bytes.peek().starts_with(PREFACE) {
+                self.set_broken("Missing HTTP/2 connection preface".to_string());
+                bytes.discard();
+
+                return;
+            }
+
+            let _ = bytes.parse_message(
+                |b, _| -> Result<(), ParseError> {
+                    let _ = b.split_to(PREFACE.len());
+
+                    Ok(())
+                },
+                |_| false,
+            );
+
+            self.preface_received = true;
+        }
+
+        loop {
+            match bytes.parse_message(parse_frame, |_| false) {
+                Ok((_, missing)) if missing > 0 => {
+                    // The frames cannot be found after a gap, and the HPACK state is lost
+                    self.set_broken(format!("{missing} bytes are missing from the capture"));
+                    bytes.discard();
+
+                    return;
+                }
+                Ok((frame, _)) => {
+                    if let Err(e) = self.handle_frame(from_client, frame) {
+                        self.set_broken(e);
+                        bytes.discard();
+
+                        return;
+                    }
+                }
+                Err(ParseError::Incomplete(_)) if !bytes.is_closed() => return,
+                Err(e) => {
+                    if !bytes.peek().is_empty() {
+                        self.set_broken(e.to_string());
+                    }
+
+                    bytes.discard();
+
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Take the streams which are finished. When the connection is closed every stream is
+    /// taken.
+    pub fn take_finished(&mut self, closed: bool) -> Vec<(PendingExchange, Option<Resp>)> {
+        let ids = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| closed || stream.is_finished())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in ids.iter() {
+            let closed_id = &mut self.closed_ids[(id % 2) as usize];
+
+            *closed_id = (*closed_id).max(*id);
+        }
+
+        ids.into_iter()
+            .filter_map(|id| self.streams.remove(&id))
+            .map(H2Stream::into_exchange)
+            .collect()
+    }
+
+    /// Stream 0 is the connection itself, and the streams which were taken are closed. Stream
+    /// ids are not reused, so a new stream has a higher id than the closed ones of its side.
+    fn is_closed(&self, id: u32) -> bool {
+        id == 0 || (!self.streams.contains_key(&id) && id <= self.closed_ids[(id % 2) as usize])
+    }
+
+    /// Give up decoding, the open streams are finished with the error.
+    fn set_broken(&mut self, error: String) {
+        for stream in self.streams.values_mut() {
+            stream
+                .error
+                .get_or_insert(format!("HTTP/2 decoding stopped: {error}"));
+        }
+
+        self.broken = Some(error);
+    }
+
+    fn handle_frame(&mut self, from_client: bool, mut frame: Frame) -> Result<(), String> {
+        let pending_block = if from_client {
+            &self.client_block
+        } else {
+            &self.server_block
+        };
+
+        if pending_block.is_some() && frame.kind != CONTINUATION {
+            return Err("Header block is not continued".to_string());
+        }
+
+        match frame.kind {
+            DATA => {
+                frame.unpad()?;
+
+                // Late frames of closed streams and of unknown ones are dropped
+                let Some(stream) = self.streams.get_mut(&frame.stream_id) else {
+                    return Ok(());
+                };
+
+                if from_client {
+                    stream.request_body.extend_from_slice(&frame.payload);
+                    stream.request_ended |= frame.has_flag(END_STREAM);
+                } else {
+                    stream.response_body.extend_from_slice(&frame.payload);
+                    stream.response_ended |= frame.has_flag(END_STREAM);
+                }
+            }
+            HEADERS => {
+                frame.unpad()?;
+
+                if frame.has_flag(PRIORITY) {
+                    if frame.payload.len() < 5 {
+                        return Err("Invalid HEADERS frame".to_string());
+                    }
+
+                    frame.payload.advance(5);
+                }
+
+                let block = HeaderBlock {
+                    stream_id: frame.stream_id,
+                    promised_id: None,
+                    end_stream: frame.has_flag(END_STREAM),
+                    fragment: frame.payload.to_vec(),
+                };
+
+                self.header_block(from_client, block, frame.has_flag(END_HEADERS))?;
+            }
+            PUSH_PROMISE => {
+                frame.unpad()?;
+
+                if frame.payload.len() < 4 {
+                    return Err("Invalid PUSH_PROMISE frame".to_string());
+                }
+
+                let promised_id = frame.payload.get_u32() & 0x7fff_ffff;
+                let block = HeaderBlock {
+                    stream_id: frame.stream_id,
+                    promised_id: Some(promised_id),
+                    end_stream: false,
+                    fragment: frame.payload.to_vec(),
+                };
+
+                self.header_block(from_client, block, frame.has_flag(END_HEADERS))?;
+            }
+            CONTINUATION => {
+                let block = if from_client {
+                    self.client_block.take()
+                } else {
+                    self.server_block.take()
+                };
+
+                let Some(mut block) = block else {
+                    return Err("CONTINUATION without header block".to_string());
+                };
+
+                block.fragment.extend_from_slice(&frame.payload);
+
+                self.header_block(from_client, block, frame.has_flag(END_HEADERS))?;
+            }
+            RST_STREAM => {
+                let code = if frame.payload.len() >= 4 {
+                    frame.payload.get_u32()
+                } else {
+                    0
+                };
+
+                // Like a cancel after the end of the stream
+                let Some(stream) = self.streams.get_mut(&frame.stream_id) else {
+                    return Ok(());
+                };
+
+                let event = format!("RST_STREAM {} from {}", error_name(code), side(from_client));
+
+                stream
+                    .error
+                    .get_or_insert(format!("Stream reset: {}", error_name(code)));
+                stream.events.push(event);
+            }
+            GOAWAY => {
+                if frame.payload.len() < 8 {
+                    return Err("Invalid GOAWAY frame".to_string());
+                }
+
+                let last_stream_id = frame.payload.get_u32() & 0x7fff_ffff;
+                let code = frame.payload.get_u32();
+                let debug = String::from_utf8_lossy(&frame.payload);
+
+                let mut event = format!(
+                    "GOAWAY {} from {}, last stream {}",
+                    error_name(code),
+                    side(from_client),
+                    last_stream_id
+                );
+
+                if !debug.is_empty() {
+                    event.push_str(&format!(": {debug}"));
+                }
+
+                for (id, stream) in self.streams.iter_mut() {
+                    stream.events.push(event.clone());
+
+                    // Streams initiated by the other side after the last one are not processed
+                    if *id > last_stream_id && (*id % 2 == 1) != from_client {
+                        stream
+                            .error
+                            .get_or_insert("Not processed, connection is going away".to_string());
+                    }
+                }
+            }
+            SETTINGS => {
+                // The table size set by one side limits the encoder of the other side
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+                    if id == SETTINGS_HEADER_TABLE_SIZE {
+                        let decoder = if from_client {
+                            &mut self.server_decoder
+                        } else {
+                            &mut self.client_decoder
+                        };
+
+                        decoder.set_max_allowed_table_size(value as usize);
+                    }
+                }
+            }
+            // PRIORITY, PING, WINDOW_UPDATE and unknown frames don't matter here
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Decode the header block if it is complete, otherwise wait for the CONTINUATION frames.
+    fn header_block(
+        &mut self,
+        from_client: bool,
+        block: HeaderBlock,
+        end_headers: bool,
+    ) -> Result<(), String> {
+        if !end_headers {
+            if from_client {
+                self.client_block = Some(block);
+            } else {
+                self.server_block = Some(block);
+            }
+
+            return Ok(());
+        }
+
+        let decoder = if from_client {
+            &mut self.client_decoder
+        } else {
+            &mut self.server_decoder
+        };
+
+        let headers = decoder
+            .decode(&block.fragment)
+            .map_err(|e| format!("HPACK error: {e}"))?
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    String::from_utf8_lossy(&name).to_string(),
+                    String::from_utf8_lossy(&value).to_string(),
+                )
+            })
+            .collect::<Headers>();
+
+        // The block is decoded even on closed streams, it changes the HPACK table
+        if self.is_closed(block.promised_id.unwrap_or(block.stream_id)) {
+            return Ok(());
+        }
+
+        if let Some(promised_id) = block.promised_id {
+            // The server pushes a response to this request
+            let stream = self.streams.entry(promised_id).or_default();
+
+            stream.request_headers = Some(headers);
+            stream.request_ended = true;
+            stream
+                .events
+                .push(format!("Pushed on stream {}", block.stream_id));
+
+            return Ok(());
+        }
+
+        let stream = self.streams.entry(block.stream_id).or_default();
+
+        if from_client {
+            if stream.request_headers.is_none() && stream.upgraded_request.is_none() {
+                stream.request_headers = Some(headers);
+            } else {
+                stream.request_trailers = headers;
+            }
+
+            stream.request_ended |= block.end_stream;
+        } else {
+            let status = headers.get(":status").and_then(|s| s.parse::<u16>().ok());
+
+            if stream.response_headers.is_some() {
+                stream.response_trailers = headers;
+            } else if status.is_some_and(|status| (100..200).contains(&status)) {
+                stream
+                    .interim_responses
+                    .push(make_response(headers, Headers::default(), vec![]));
+            } else {
+                stream.response_headers = Some(headers);
+            }
+
+            stream.response_ended |= block.end_stream;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_frame(bytes: &mut BytesMut, _eof: bool) -> Result<Frame, ParseError> {
+    if bytes.len() < FRAME_HEADER_LEN {
+        return Err(ParseError::Incomplete("Partial frame header"));
+    }
+
+    let len = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize;
+
+    if bytes.len() < FRAME_HEADER_LEN + len {
+        return Err(ParseError::Incomplete("Partial frame"));
+    }
+
+    let mut header = bytes.split_to(FRAME_HEADER_LEN);
+
+    header.advance(3);
+
+    Ok(Frame {
+        kind: header.get_u8(),
+        flags: header.get_u8(),
+        stream_id: header.get_u32() & 0x7fff_ffff,
+        payload: bytes.split_to(len),
+    })
+}
+
+fn make_request(headers: Headers, trailers: Headers, body: Vec<u8>) -> Req {
+    let method = headers.get(":method").unwrap_or_default().to_string();
+    // CONNECT requests have no path, only authority
+    let path = headers
+        .get(":path")
+        .or(headers.get(":authority"))
+        .unwrap_or_default()
+        .to_string();
+
+    Req {
+        method,
+        path,
+        authority: None,
+        version: "2".to_string(),
+        body: (!body.is_empty()).then(|| decode_body(&headers, &body)),
+        headers,
+        trailers,
+        truncated: false,
+    }
+}
+
+fn make_response(headers: Headers, trailers: Headers, body: Vec<u8>) -> Resp {
+    Resp {
+        version: "2".to_string(),
+        code: headers
+            .get(":status")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_default(),
+        reason: None,
+        body: Some(decode_body(&headers, &body)),
+        headers,
+        trailers,
+        truncated: false,
+        close_delimited: false,
+    }
+}
+
+fn side(from_client: bool) -> &'static str {
+    if from_client {
+        "client"
+    } else {
+        "server"
+    }
+}
+
+/// The name of an HTTP/2 error code.
+fn error_name(code: u32) -> String {
+    let name = match code {
+        0x0 => "NO_ERROR",
+        0x1 => "PROTOCOL_ERROR",
+        0x2 => "INTERNAL_ERROR",
+        0x3 => "FLOW_CONTROL_ERROR",
+        0x4 => "SETTINGS_TIMEOUT",
+        0x5 => "STREAM_CLOSED",
+        0x6 => "FRAME_SIZE_ERROR",
+        0x7 => "REFUSED_STREAM",
+        0x8 => "CANCEL",
+        0x9 => "COMPRESSION_ERROR",
+        0xa => "CONNECT_ERROR",
+        0xb => "ENHANCE_YOUR_CALM",
+        0xc => "INADEQUATE_SECURITY",
+        0xd => "HTTP_1_1_REQUIRED",
+        _ => return format!("error 0x{code:x}"),
+    };
+
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use loona_hpack::Encoder;
+
+    use super::*;
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn headers(encoder: &mut Encoder, fields: &[(&str, &str)]) -> Vec<u8> {
+        encoder.encode(fields.iter().map(|(n, v)| (n.as_bytes(), v.as_bytes())))
+    }
+
+    fn receive(connection: &mut Connection, from_client: bool, bytes: &[u8]) {
+        let mut stream = ByteStream::default();
+
+        stream.push(bytes);
+        connection.receive(from_client, &mut stream);
+    }
+
+    fn request(encoder: &mut Encoder, stream_id: u32, path: &str) -> Vec<u8> {
+        let block = headers(
+            encoder,
+            &[(":method", "GET"), (":path", path), (":authority", "h")],
+        );
+
+        frame(HEADERS, END_HEADERS | END_STREAM, stream_id, &block)
+    }
+
+    #[test]
+    fn continued_and_padded_frames() {
+        let mut connection = Connection::new();
+        let mut client = Encoder::new();
+        let mut server = Encoder::new();
+
+        let block = headers(
+            &mut client,
+            &[(":method", "POST"), (":path", "/x"), (":authority", "h")],
+        );
+        let mut bytes = PREFACE.to_vec();
+
+        bytes.extend(frame(SETTINGS, 0, 0, &[]));
+        bytes.extend(frame(HEADERS, 0, 1, &block[..3]));
+        bytes.extend(frame(CONTINUATION, END_HEADERS, 1, &block[3..]));
+        bytes.extend(frame(DATA, PADDED | END_STREAM, 1, b"\x02hi\0\0"));
+        receive(&mut connection, true, &bytes);
+        assert!(connection.take_finished(false).is_empty());
+
+        let block = headers(&mut server, &[(":status", "200")]);
+        let mut bytes = frame(HEADERS, END_HEADERS, 1, &block);
+
+        bytes.extend(frame(DATA, END_STREAM, 1, b"ok"));
+        receive(&mut connection, false, &bytes);
+
+        let exchanges = connection.take_finished(false);
+
+        assert_eq!(exchanges.len(), 1);
+
+        let (pending, response) = &exchanges[0];
+        let request = pending.request.as_ref().unwrap();
+        let response = response.as_ref().unwrap();
+
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/x")
+        );
+        assert_eq!(request.body.as_ref().unwrap().data, b"hi");
+        assert_eq!(response.code, 200);
+        assert_eq!(response.body.as_ref().unwrap().data, b"ok");
+    }
+
+    #[test]
+    fn late_frames_of_closed_streams() {
+        let mut connection = Connection::new();
+        let mut client = Encoder::new();
+        let mut server = Encoder::new();
+
+        let mut bytes = PREFACE.to_vec();
+
+        bytes.extend(request(&mut client, 1, "/a"));
+        receive(&mut connection, true, &bytes);
+
+        let block = headers(&mut server, &[(":status", "204")]);
+
+        receive(
+            &mut connection,
+            false,
+            &frame(HEADERS, END_HEADERS | END_STREAM, 1, &block),
+        );
+        assert_eq!(connection.take_finished(false).len(), 1);
+
+        // Cancel after the end of the stream, data and resets of stream 0, a late trailer
+        let mut bytes = frame(RST_STREAM, 0, 1, &8u32.to_be_bytes());
+
+        bytes.extend(frame(RST_STREAM, 0, 0, &8u32.to_be_bytes()));
+        bytes.extend(frame(DATA, 0, 0, b"zz"));
+        bytes.extend(frame(
+            HEADERS,
+            END_HEADERS,
+            1,
+            &headers(&mut client, &[("x-late", "1")]),
+        ));
+        bytes.extend(request(&mut client, 3, "/b"));
+        receive(&mut connection, true, &bytes);
+        receive(&mut connection, false, &frame(DATA, END_STREAM, 1, b"late"));
+
+        let exchanges = connection.take_finished(true);
+
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].0.request.as_ref().unwrap().path, "/b");
+        assert!(connection.broken.is_none());
+    }
+
+    #[test]
+    fn streams_after_goaway_are_not_processed() {
+        let mut connection = Connection::new();
+        let mut client = Encoder::new();
+
+        let mut bytes = PREFACE.to_vec();
+
+        bytes.extend(request(&mut client, 1, "/a"));
+        bytes.extend(request(&mut client, 3, "/b"));
+        receive(&mut connection, true, &bytes);
+
+        let goaway = [1u32.to_be_bytes(), 0u32.to_be_bytes()].concat();
+
+        receive(&mut connection, false, &frame(GOAWAY, 0, 0, &goaway));
+
+        let exchanges = connection.take_finished(false);
+
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].0.request.as_ref().unwrap().path, "/b");
+        assert_eq!(
+            exchanges[0].0.error.as_deref(),
+            Some("Not processed, connection is going away")
+        );
+    }
+
+    #[test]
+    fn missing_preface() {
+        let mut connection = Connection::new();
+
+        receive(&mut connection, true, b"GET / HTTP/1.1\r\n\r\nxxxxxxxxxxx");
+        assert!(connection.broken.is_some());
+    }
+}
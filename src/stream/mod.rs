@@ -1,12 +1,41 @@
 // Handling TCP streams (loopback and real interfaces)
 
-use std::{collections::HashMap, io::Read, net::IpAddr, str::FromStr};
+mod chunked;
+mod encoding;
+mod form;
+mod grpc;
+mod h2;
+mod protobuf;
+mod reassembly;
+mod sse;
+mod tls;
+mod websocket;
+
+use std::{net::IpAddr, str::FromStr, time::Duration};
 
 use bytes::BytesMut;
-use etherparse::{Ipv4Header, SlicedPacket, TcpHeader};
-use flate2::read::MultiGzDecoder;
+use etherparse::SlicedPacket;
+
+pub use self::{grpc::decode as decode_grpc, protobuf::Schema, tls::KeyLog};
+
+use self::reassembly::ByteStream;
+use crate::{
+    savefile::RawPacket,
+    ui::stream::{self, Body, Headers, HttpStream, Packets, Req, Resp, SseEvent, WsMessage},
+};
 
-use crate::ui::stream::{HttpStream, Req, Resp};
+/// An exchange is emitted when the headers of its response are received, then again with
+/// the new bytes of the body at most this often, by the time of the capture.
+const LIVE_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+/// The exchanges in progress show this much of the body received so far.
+const MAX_LIVE_BODY_BYTES: usize = 64 * 1024;
+/// The packets of the exchanges of a connection are kept whole up to this size, the later
+/// ones are cut to `TRUNCATED_SNAPLEN`, like in a capture with a short snapshot length.
+const MAX_PACKET_BYTES: usize = 32 * 1024 * 1024;
+/// Enough for the link layer, IP and TCP headers of the packets.
+const TRUNCATED_SNAPLEN: usize = 128;
+/// The packets of the exchanges over this number are not kept for saving.
+const MAX_PACKETS: usize = 200_000;
 
 /// Identifies an endpoint.
 #[derive(Debug, PartialEq)]
@@ -15,53 +44,314 @@ pub enum EndpointSide {
     Destination,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Endpoint {
     address: IpAddr,
     port: u16,
 }
 
+impl Endpoint {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
 #[derive(Debug)]
 pub struct Party {
-    side: EndpointSide,
     endpoint: Endpoint,
 }
 
+/// A request waiting for its final response.
+struct PendingExchange {
+    /// The request, it is `None` if the request could not be parsed.
+    request: Option<Req>,
+    /// The bytes missing from the capture in the messages so far.
+    missing: u64,
+    /// The 1xx responses received before the final response.
+    interim_responses: Vec<Resp>,
+    error: Option<String>,
+    unparsed_request: Vec<u8>,
+    unparsed_response: Vec<u8>,
+    /// Protocol events of the exchange, like resetting the HTTP/2 stream.
+    events: Vec<String>,
+    /// The WebSocket messages after the handshake.
+    messages: Vec<WsMessage>,
+    tunnel: Option<stream::Tunnel>,
+    /// The server-sent events of the response with the time they were received.
+    sse_events: Vec<SseEvent>,
+    /// The events are parsed while the response is received, then from the final body.
+    sse_parser: sse::Parser,
+    /// The response headers are received, but the body is not complete yet.
+    live: Option<Box<LiveResponse>>,
+}
+
+/// The response of an exchange which is emitted before it is complete.
+struct LiveResponse {
+    /// The status line and the headers of the response.
+    head: Resp,
+    /// The position of the body in the response bytes.
+    body_start: usize,
+    content_length: Option<usize>,
+    /// The decoder of a chunked body and the chunks decoded so far.
+    chunked: Option<(chunked::Decoder, Vec<u8>)>,
+    /// The body is a stream of server-sent events without content coding.
+    event_stream: bool,
+    /// The length of the body at the last update.
+    len: usize,
+    /// The number of server-sent events emitted in the updates so far.
+    events: usize,
+    /// The time of the last update.
+    time: Duration,
+}
+
+impl LiveResponse {
+    /// Parse the head of the response, if it is received and the response has a body.
+    fn new(bytes: &[u8], method: &str, now: Duration) -> Option<Self> {
+        let (head, body_start) = parse_response_head(bytes).ok()?;
+
+        if has_no_body(method, head.code) {
+            return None;
+        }
+
+        let chunked = is_chunked(&head.headers);
+        // Without length the body lasts till the connection is closed
+        let content_length = match head.headers.get("transfer-encoding") {
+            Some(_) => None,
+            None => get_content_length(&head.headers).ok()?,
+        };
+        let event_stream =
+            sse::is_event_stream(&head.headers) && head.headers.get("content-encoding").is_none();
+
+        Some(LiveResponse {
+            head,
+            body_start,
+            content_length,
+            chunked: chunked.then(|| (chunked::Decoder::default(), vec![])),
+            event_stream,
+            len: 0,
+            events: 0,
+            time: now,
+        })
+    }
+
+    /// The body received so far without the transfer coding, `bytes` are the bytes of the
+    /// response.
+    fn body<'a>(&'a self, bytes: &'a [u8]) -> &'a [u8] {
+        match self.chunked {
+            Some((_, ref data)) => data,
+            None => {
+                let body = bytes.get(self.body_start..).unwrap_or_default();
+
+                &body[..self.content_length.unwrap_or(body.len()).min(body.len())]
+            }
+        }
+    }
+}
+
+impl PendingExchange {
+    fn new(request: Option<Req>, missing: u64) -> Self {
+        PendingExchange {
+            request,
+            missing,
+            interim_responses: vec![],
+            error: None,
+            unparsed_request: vec![],
+            unparsed_response: vec![],
+            events: vec![],
+            messages: vec![],
+            tunnel: None,
+            sse_events: vec![],
+            sse_parser: sse::Parser::default(),
+            live: None,
+        }
+    }
+
+    /// A copy of the exchange for an update while it is in progress, without the state of
+    /// parsing the response. The WebSocket messages and server-sent events are left out, an
+    /// update carries only the ones received since the previous update.
+    fn snapshot(&self) -> PendingExchange {
+        PendingExchange {
+            request: self.request.clone(),
+            missing: self.missing,
+            interim_responses: self.interim_responses.clone(),
+            error: self.error.clone(),
+            unparsed_request: self.unparsed_request.clone(),
+            unparsed_response: self.unparsed_response.clone(),
+            events: self.events.clone(),
+            tunnel: self.tunnel,
+            ..PendingExchange::new(None, 0)
+        }
+    }
+}
+
+/// The protocol spoken on the connection.
+enum Protocol {
+    Http1,
+    Http2(Box<h2::Connection>),
+    WebSocket(Box<websocket::Connection>),
+    Tunnel(Box<Tunnel>),
+}
+
+/// A tunnel opened by a CONNECT request. The bytes sent through it are only counted.
+struct Tunnel {
+    pending: PendingExchange,
+    response: Resp,
+    /// The time of the response which opened the tunnel.
+    opened: Duration,
+    client_bytes: u64,
+    server_bytes: u64,
+}
+
 pub struct TcpStream {
     id: usize,
     timestamp: i64,
     source: Party,
     destination: Party,
-    request: BytesMut,
-    response: BytesMut,
+    request: ByteStream,
+    response: ByteStream,
     fin: (bool, bool),
+    /// The packets which don't belong to an emitted exchange yet.
+    packets: Packets,
+    /// The number of bytes kept of these packets.
+    packet_bytes: usize,
+    /// The request which is parsed, but its response is not complete yet.
+    pending: Option<PendingExchange>,
+    /// The number of exchanges emitted so far.
+    exchanges: usize,
+    /// The timestamp of the last packet of the connection.
+    last_seen: Duration,
+    /// The client side was only guessed, there was no handshake, port or HTTP message
+    /// telling that.
+    direction_guessed: bool,
+    protocol: Protocol,
+    /// The TLS session of the connection, if it is decrypted. The request and response
+    /// byte streams have the decrypted application data then.
+    tls: Option<Box<tls::Session>>,
 }
 
 impl TcpStream {
-    pub fn new(id: usize, timestamp: i64, source: Endpoint, destination: Endpoint) -> TcpStream {
+    /// Create a tcp stream where `source` is the client side of the connection.
+    pub fn new(
+        id: usize,
+        timestamp: i64,
+        source: Endpoint,
+        destination: Endpoint,
+        direction_guessed: bool,
+    ) -> TcpStream {
         TcpStream {
             id,
             timestamp,
-            source: Party {
-                side: EndpointSide::Source,
-                endpoint: source,
-            },
+            source: Party { endpoint: source },
             destination: Party {
-                side: EndpointSide::Destination,
                 endpoint: destination,
             },
-            request: BytesMut::new(),
-            response: BytesMut::new(),
+            request: ByteStream::default(),
+            response: ByteStream::default(),
             fin: (false, false),
+            packets: Packets::default(),
+            packet_bytes: 0,
+            pending: None,
+            exchanges: 0,
+            last_seen: Duration::ZERO,
+            direction_guessed,
+            protocol: Protocol::Http1,
+            tls: None,
+        }
+    }
+
+    /// Add a tcp segment sent by the request side. Its acknowledgement number refers to the
+    /// response bytes.
+    pub fn add_request_segment(&mut self, seq: u32, syn: bool, ack: Option<u32>, payload: &[u8]) {
+        let (request, response) = self.segment_streams();
+
+        request.segment(seq, syn, payload);
+
+        if let Some(ack) = ack {
+            response.acknowledged(ack);
         }
     }
 
-    pub fn append_request_bytes(&mut self, b: &[u8]) {
-        self.request.extend_from_slice(b);
+    /// Add a tcp segment sent by the response side.
+    pub fn add_response_segment(&mut self, seq: u32, syn: bool, ack: Option<u32>, payload: &[u8]) {
+        let (request, response) = self.segment_streams();
+
+        response.segment(seq, syn, payload);
+
+        if let Some(ack) = ack {
+            request.acknowledged(ack);
+        }
+    }
+
+    /// The byte streams the tcp segments of the client and the server are added to.
+    fn segment_streams(&mut self) -> (&mut ByteStream, &mut ByteStream) {
+        match self.tls {
+            Some(ref mut tls) => tls.raw_streams(),
+            None => (&mut self.request, &mut self.response),
+        }
     }
 
-    pub fn append_response_bytes(&mut self, b: &[u8]) {
-        self.response.extend_from_slice(b);
+    /// Decrypt the TLS records received so far with the secrets of the key log. The
+    /// connection is decrypted if it starts with a ClientHello.
+    pub fn decrypt(&mut self, keylog: &mut KeyLog) {
+        if self.tls.is_none() {
+            let starts_with_hello = self.exchanges == 0
+                && self.pending.is_none()
+                && matches!(self.protocol, Protocol::Http1)
+                && tls::is_client_hello_start(self.request.peek());
+
+            if !starts_with_hello {
+                return;
+            }
+
+            let request = std::mem::take(&mut self.request);
+            let response = std::mem::take(&mut self.response);
+
+            self.tls = Some(Box::new(tls::Session::new(request, response)));
+        }
+
+        if let Some(ref mut tls) = self.tls {
+            tls.decrypt(keylog, &mut self.request, &mut self.response);
+        }
+    }
+
+    /// Keep the packet to save it with the exchanges. Long downloads and connections are
+    /// kept only partly, see `MAX_PACKET_BYTES` and `MAX_PACKETS`.
+    pub fn add_packet(&mut self, mut packet: RawPacket) {
+        self.last_seen = self.last_seen.max(packet.timestamp);
+
+        let mut packets = self.packets.write().unwrap();
+
+        if packets.len() >= MAX_PACKETS {
+            return;
+        }
+
+        if self.packet_bytes >= MAX_PACKET_BYTES {
+            packet.data.truncate(TRUNCATED_SNAPLEN);
+        }
+
+        self.packet_bytes += packet.data.len();
+        packets.push(packet);
+    }
+
+    pub fn last_seen(&self) -> Duration {
+        self.last_seen
+    }
+
+    pub fn is_direction_guessed(&self) -> bool {
+        self.direction_guessed
+    }
+
+    pub fn confirm_direction(&mut self) {
+        self.direction_guessed = false;
+    }
+
+    /// The client side turned out to be the destination, swap the sides of the connection.
+    pub fn swap_direction(&mut self) {
+        std::mem::swap(&mut self.source, &mut self.destination);
+        std::mem::swap(&mut self.request, &mut self.response);
+
+        self.fin = (self.fin.1, self.fin.0);
+        self.direction_guessed = false;
     }
 
     pub fn set_source_fin(&mut self) {
@@ -76,31 +366,453 @@ impl TcpStream {
         self.fin == (true, true)
     }
 
-    pub fn convert_to_http_stream(mut self) -> Vec<HttpStream> {
+    /// Parse the request-response pairs which are fully received so far. The connection
+    /// can go on, the bytes of an incomplete exchange are kept till more segments arrive.
+    /// The packets received since the previous exchanges belong to the new ones.
+    ///
+    /// The bytes which cannot be parsed are skipped till the next message, and they are
+    /// emitted as an exchange with the parse error. A broken request still gets the next
+    /// response, so the requests after it are paired with their own responses. When the connection is closed, the
+    /// requests without response are emitted, too.
+    pub fn take_exchanges(&mut self, interface: &str) -> Vec<HttpStream> {
         let mut streams = vec![];
+        let mut packets = None;
+
+        if let Protocol::Http1 = self.protocol {
+            self.take_http1_exchanges(interface, &mut packets, &mut streams);
+        }
+
+        if let Protocol::Http2(ref mut connection) = self.protocol {
+            connection.receive(true, &mut self.request);
+            connection.receive(false, &mut self.response);
+
+            let closed = self.request.is_closed() && self.response.is_closed();
+
+            for (pending, response) in connection.take_finished(closed) {
+                streams.push(self.exchange(interface, &mut packets, pending, response));
+            }
+        }
+
+        if let Protocol::WebSocket(ref mut connection) = self.protocol {
+            connection.receive(true, &mut self.request, self.last_seen);
+            connection.receive(false, &mut self.response, self.last_seen);
+
+            let closed = self.request.is_closed() && self.response.is_closed();
 
+            if let Some((pending, response)) = connection.take_finished(closed) {
+                streams.push(self.exchange(interface, &mut packets, pending, Some(response)));
+            } else if let Some((pending, response)) =
+                connection.live_update(self.last_seen, LIVE_UPDATE_INTERVAL)
+            {
+                streams.push(self.live_stream(interface, pending, response));
+            }
+        }
+
+        if let Protocol::Tunnel(ref mut tunnel) = self.protocol {
+            tunnel.client_bytes += self.request.discard() as u64;
+            tunnel.server_bytes += self.response.discard() as u64;
+
+            if self.request.is_closed() && self.response.is_closed() {
+                // Nothing is parsed after the tunnel is closed
+                if let Protocol::Tunnel(tunnel) =
+                    std::mem::replace(&mut self.protocol, Protocol::Http1)
+                {
+                    let mut pending = tunnel.pending;
+
+                    pending.tunnel = Some(stream::Tunnel {
+                        client_bytes: tunnel.client_bytes,
+                        server_bytes: tunnel.server_bytes,
+                        duration: self.last_seen.saturating_sub(tunnel.opened),
+                    });
+
+                    streams.push(self.exchange(
+                        interface,
+                        &mut packets,
+                        pending,
+                        Some(tunnel.response),
+                    ));
+                }
+            }
+        }
+
+        if let Some(error) = self.tls.as_mut().and_then(|tls| tls.take_error()) {
+            let mut pending = PendingExchange::new(None, 0);
+
+            pending.error = Some(error);
+
+            streams.push(self.exchange(interface, &mut packets, pending, None));
+        }
+
+        streams
+    }
+
+    fn take_http1_exchanges(
+        &mut self,
+        interface: &str,
+        packets: &mut Option<Packets>,
+        streams: &mut Vec<HttpStream>,
+    ) {
         loop {
-            if let Ok(parsed_request) = parse_request(&mut self.request) {
-                if let Ok(parsed_response) = parse_response(&mut self.response) {
-                    let stream = HttpStream {
-                        id: streams.len() + 1,
-                        timestamp: self.timestamp,
-                        source_addr: self.source.endpoint.address,
-                        source_port: self.source.endpoint.port,
-                        dest_addr: self.destination.endpoint.address,
-                        dest_port: self.destination.endpoint.port,
-                        parsed_request: Some(parsed_request),
-                        parsed_response: Some(parsed_response),
-                    };
+            if self.pending.is_none() {
+                let bytes = self.request.peek();
+
+                if bytes.starts_with(h2::PREFACE) {
+                    // HTTP/2 with prior knowledge
+                    self.protocol = Protocol::Http2(Box::new(h2::Connection::new()));
+
+                    return;
+                }
+
+                if !bytes.is_empty() && h2::PREFACE.starts_with(bytes) && !self.request.is_closed()
+                {
+                    return;
+                }
+            }
 
+            let mut pending = match self.pending.take() {
+                Some(pending) => pending,
+                None => match self.request.parse_message(parse_request, is_request_start) {
+                    Ok((request, missing)) => PendingExchange::new(Some(request), missing),
+                    Err(ParseError::Incomplete(_)) if !self.request.is_closed() => break,
+                    Err(e) => {
+                        let Some(bytes) = self.request.skip_message(is_request_start) else {
+                            break;
+                        };
+
+                        // The response of the broken request is read too, so that the
+                        // following requests get their own responses
+                        let mut pending = PendingExchange::new(None, 0);
+
+                        pending.error = Some(e.to_string());
+                        pending.unparsed_request = bytes;
+                        pending
+                    }
+                },
+            };
+
+            let method = pending
+                .request
+                .as_ref()
+                .map(|req| req.method.clone())
+                .unwrap_or_default();
+            let mut parse = |bytes: &mut BytesMut, eof| parse_response(bytes, eof, &method);
+
+            let error = loop {
+                match self.response.parse_message(&mut parse, is_response_start) {
+                    Ok((response, missing)) => {
+                        pending.missing += missing;
+
+                        if !is_interim(&response) {
+                            break Ok(response);
+                        }
+
+                        pending.interim_responses.push(response);
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+
+            let mut waiting = false;
+            let response = match error {
+                Ok(response) => Some(response),
+                Err(ParseError::Incomplete(_)) if !self.response.is_closed() => {
+                    waiting = true;
+
+                    None
+                }
+                Err(e) => match self.response.skip_message(is_response_start) {
+                    Some(bytes) => {
+                        pending.error.get_or_insert(e.to_string());
+                        pending.unparsed_response = bytes;
+
+                        None
+                    }
+                    None if self.response.is_closed() => {
+                        pending.error.get_or_insert("No response".to_string());
+
+                        None
+                    }
+                    None => {
+                        waiting = true;
+
+                        None
+                    }
+                },
+            };
+
+            if waiting {
+                // Waiting for the response, the part of it received so far can be shown
+                let now = self.last_seen;
+
+                if let Some(stream) = self.live_exchange(interface, &mut pending, &method, now) {
                     streams.push(stream);
-                } else {
-                    return streams;
                 }
-            } else {
-                return streams;
+
+                self.pending = Some(pending);
+
+                break;
             }
+
+            if let Some(ref resp) = response {
+                if resp.code == 101 && is_upgrade_to(resp, "h2c") {
+                    let Some(request) = pending.request else {
+                        return;
+                    };
+
+                    pending.interim_responses.extend(response);
+
+                    let connection = h2::Connection::upgraded(request, pending.interim_responses);
+
+                    self.protocol = Protocol::Http2(Box::new(connection));
+
+                    return;
+                }
+            }
+
+            match response {
+                Some(resp) if is_tunnel(&method, &resp) => {
+                    let tunnel = Tunnel {
+                        pending,
+                        response: resp,
+                        opened: self.last_seen,
+                        client_bytes: 0,
+                        server_bytes: 0,
+                    };
+
+                    self.protocol = Protocol::Tunnel(Box::new(tunnel));
+
+                    return;
+                }
+                Some(resp) if resp.code == 101 && is_upgrade_to(&resp, "websocket") => {
+                    // The handshake is emitted in progress, then with the messages
+                    let connection = websocket::Connection::new(pending, resp);
+
+                    self.protocol = Protocol::WebSocket(Box::new(connection));
+
+                    return;
+                }
+                response => streams.push(self.exchange(interface, packets, pending, response)),
+            }
+        }
+
+        if self.request.is_closed() && self.pending.is_none() {
+            // The requests of these responses are not in the capture
+            while let Some(bytes) = self.response.skip_message(is_response_start) {
+                let mut pending = PendingExchange::new(None, 0);
+
+                pending.error = Some("Response without request".to_string());
+                pending.unparsed_response = bytes;
+
+                streams.push(self.exchange(interface, packets, pending, None));
+            }
+        }
+    }
+
+    /// Emit the update of the exchange in progress when no packets arrive, its response
+    /// may have grown since the last update. `now` is the time of the capture.
+    pub fn live_update(&mut self, interface: &str, now: Duration) -> Option<HttpStream> {
+        if let Protocol::WebSocket(ref mut connection) = self.protocol {
+            let (pending, response) = connection.live_update(now, LIVE_UPDATE_INTERVAL)?;
+
+            return Some(self.live_stream(interface, pending, response));
+        }
+
+        if !matches!(self.protocol, Protocol::Http1) {
+            return None;
         }
+
+        let mut pending = self.pending.take()?;
+        let stream = match pending.live {
+            Some(_) => self.live_exchange(interface, &mut pending, "", now),
+            None => None,
+        };
+
+        self.pending = Some(pending);
+
+        stream
+    }
+
+    /// Make an http stream from the exchange whose response headers are received, but the
+    /// body is not complete, like server-sent events, a long poll or a long download. It is
+    /// emitted as soon as the headers are received, then again when the body grows, at most
+    /// once in `LIVE_UPDATE_INTERVAL`. The final exchange replaces it in the list.
+    ///
+    /// Only the bytes received since the previous call are parsed. The updates share the
+    /// packets of the connection, and they show the beginning of the body.
+    fn live_exchange(
+        &self,
+        interface: &str,
+        pending: &mut PendingExchange,
+        method: &str,
+        now: Duration,
+    ) -> Option<HttpStream> {
+        let bytes = self.response.peek();
+        let first = pending.live.is_none();
+
+        if first {
+            pending.live = Some(Box::new(LiveResponse::new(bytes, method, now)?));
+        }
+
+        let live = pending.live.as_mut()?;
+
+        if let Some((ref mut decoder, ref mut data)) = live.chunked {
+            let chunked = bytes.get(live.body_start..).unwrap_or_default();
+
+            decoder.decode(chunked, data).ok()?;
+        }
+
+        if live.event_stream {
+            for event in pending.sse_parser.parse(live.body(bytes)) {
+                pending.sse_events.push(SseEvent {
+                    timestamp: now,
+                    ..event
+                });
+            }
+        }
+
+        let len = live.body(bytes).len();
+        let elapsed = now.saturating_sub(live.time) >= LIVE_UPDATE_INTERVAL;
+
+        if !first && (len <= live.len || !elapsed) {
+            return None;
+        }
+
+        let sent = live.events;
+
+        live.len = len;
+        live.events = pending.sse_events.len();
+        live.time = now;
+
+        let mut snapshot = pending.snapshot();
+
+        snapshot.sse_events = pending.sse_events[sent..].to_vec();
+
+        let live = pending.live.as_ref()?;
+        let body = live.body(bytes);
+        let mut response = live.head.clone();
+        let mut preview = decode_body(&response.headers, &body[..len.min(MAX_LIVE_BODY_BYTES)]);
+
+        preview.wire_size = len;
+        response.body = Some(preview);
+
+        Some(self.live_stream(interface, snapshot, response))
+    }
+
+    /// Make an http stream from a copy of the exchange in progress. It shares the packets
+    /// of the connection, and it is numbered as the final exchange which replaces it.
+    fn live_stream(&self, interface: &str, pending: PendingExchange, response: Resp) -> HttpStream {
+        let mut stream = self.http_stream(
+            interface,
+            self.exchanges + 1,
+            self.packets.clone(),
+            pending,
+            Some(response),
+        );
+
+        stream.in_progress = true;
+
+        stream
+    }
+
+    /// Add the server-sent events of the response which are not added while it was in
+    /// progress, they are stamped with the time of the last packet.
+    fn add_sse_events(&self, pending: &mut PendingExchange, response: &Resp) {
+        if !sse::is_event_stream(&response.headers) {
+            return;
+        }
+
+        let Some(body) = response.body.as_ref().filter(|b| b.decode_error.is_none()) else {
+            return;
+        };
+
+        for event in pending.sse_parser.parse(&body.data) {
+            pending.sse_events.push(SseEvent {
+                timestamp: self.last_seen,
+                ..event
+            });
+        }
+    }
+
+    /// Make an http stream from an exchange. The packets received since the previous
+    /// exchanges are shared by the exchanges taken at the same time.
+    fn exchange(
+        &mut self,
+        interface: &str,
+        packets: &mut Option<Packets>,
+        mut pending: PendingExchange,
+        response: Option<Resp>,
+    ) -> HttpStream {
+        if packets.is_none() {
+            self.packet_bytes = 0;
+        }
+
+        let packets = packets
+            .get_or_insert_with(|| std::mem::take(&mut self.packets))
+            .clone();
+
+        if let Some(ref response) = response {
+            self.add_sse_events(&mut pending, response);
+        }
+
+        self.exchanges += 1;
+
+        self.http_stream(interface, self.exchanges, packets, pending, response)
+    }
+
+    fn http_stream(
+        &self,
+        interface: &str,
+        id: usize,
+        packets: Packets,
+        pending: PendingExchange,
+        response: Option<Resp>,
+    ) -> HttpStream {
+        let timestamp = packets
+            .read()
+            .unwrap()
+            .first()
+            .map(|p| p.timestamp.as_secs() as i64)
+            .unwrap_or(self.timestamp);
+
+        HttpStream {
+            id,
+            connection: self.id,
+            interface: interface.to_string(),
+            capture: 0,
+            timestamp,
+            source_addr: self.source.endpoint.address,
+            source_port: self.source.endpoint.port,
+            dest_addr: self.destination.endpoint.address,
+            dest_port: self.destination.endpoint.port,
+            parsed_request: pending.request,
+            interim_responses: pending.interim_responses,
+            parsed_response: response,
+            missing_bytes: pending.missing,
+            error: pending.error,
+            unparsed_request: pending.unparsed_request,
+            unparsed_response: pending.unparsed_response,
+            events: pending.events,
+            messages: pending.messages,
+            tunnel: pending.tunnel,
+            sse_events: pending.sse_events,
+            grpc_messages: vec![],
+            grpc_status: None,
+            in_progress: false,
+            charset: None,
+            packets,
+        }
+    }
+
+    /// The connection is over, parse the remaining exchanges.
+    pub fn convert_to_http_stream(mut self, interface: &str) -> Vec<HttpStream> {
+        if let Some(ref mut tls) = self.tls {
+            tls.close();
+        }
+
+        self.request.close();
+        self.response.close();
+
+        self.take_exchanges(interface)
     }
 
     pub fn from_sliced_packet(p: &SlicedPacket) -> Option<(Endpoint, Endpoint)> {
@@ -135,22 +847,6 @@ impl TcpStream {
         ))
     }
 
-    pub fn extract_endpoint_pair(
-        ip4_header: Ipv4Header,
-        tcp_header: TcpHeader,
-    ) -> Option<(Endpoint, Endpoint)> {
-        Some((
-            Endpoint {
-                address: ip4_header.source.into(),
-                port: tcp_header.source_port,
-            },
-            Endpoint {
-                address: ip4_header.destination.into(),
-                port: tcp_header.destination_port,
-            },
-        ))
-    }
-
     /// Checks if the two endpoints as source and destination are in the tcp stream and gives back
     /// the `EndpointSide` as `Source` if the first endpoint is the source in the tcp stream and
     /// the second is the destination. If they are parties in the tcp stream but opposite order,
@@ -176,111 +872,473 @@ impl std::fmt::Debug for TcpStream {
     }
 }
 
+/// Error of parsing a message from the bytes received so far.
+#[derive(Debug)]
+enum ParseError {
+    /// The message is not complete, more bytes are needed.
+    Incomplete(&'static str),
+    /// The bytes are not a valid message.
+    Invalid(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Incomplete(e) => write!(f, "{e}"),
+            ParseError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(e: String) -> Self {
+        ParseError::Invalid(e)
+    }
+}
+
+impl From<httparse::Error> for ParseError {
+    fn from(e: httparse::Error) -> Self {
+        ParseError::Invalid(e.to_string())
+    }
+}
+
+/// The body of a message taken from the buffer.
+#[derive(Default)]
+struct MessageBody {
+    data: Option<BytesMut>,
+    trailers: Headers,
+    /// The body is shorter than it should be, the rest was not captured.
+    truncated: bool,
+    /// The end of the body is marked by closing the connection.
+    close_delimited: bool,
+}
+
+/// Take the headers and the body of the message from the buffer. The length of the body is
+/// determined by the message length rules of RFC 9112 section 6.3: the chunked transfer
+/// coding takes precedence over the Content-Length header. If the body is not complete,
+/// the buffer is left intact unless `eof` is set, in that case the available bytes are
+/// taken as a truncated body.
+///
+/// Without length a response body lasts till the connection is closed, a request has no
+/// body then. `is_response` tells which one the message is.
+fn take_body(
+    bytes: &mut BytesMut,
+    body_start: usize,
+    headers: &Headers,
+    is_response: bool,
+    eof: bool,
+) -> Result<MessageBody, ParseError> {
+    let available = bytes.len() - body_start;
+
+    if is_chunked(headers) {
+        if !eof && !chunked::scan(&bytes[body_start..])?.complete {
+            return Err(ParseError::Incomplete("Partial chunked body"));
+        }
+
+        let chunked = chunked::decode(&bytes[body_start..])?;
+
+        let _ = bytes.split_to(body_start + chunked.len);
+
+        return Ok(MessageBody {
+            data: Some(BytesMut::from(&chunked.data[..])),
+            trailers: chunked.trailers.into_iter().collect(),
+            truncated: !chunked.complete,
+            close_delimited: false,
+        });
+    }
+
+    // Other transfer codings don't tell the length, and Content-Length is ignored then
+    let content_length = if headers.get("transfer-encoding").is_some() {
+        if !is_response {
+            return Err(ParseError::Invalid(
+                "Request body length cannot be determined".to_string(),
+            ));
+        }
+
+        None
+    } else {
+        get_content_length(headers)?
+    };
+
+    if content_length.is_none() && is_response {
+        if !eof {
+            return Err(ParseError::Incomplete("Partial close-delimited body"));
+        }
+
+        let _ = bytes.split_to(body_start);
+
+        return Ok(MessageBody {
+            data: Some(bytes.split()),
+            close_delimited: true,
+            ..Default::default()
+        });
+    }
+
+    if available < content_length.unwrap_or(0) && !eof {
+        return Err(ParseError::Incomplete("Partial body"));
+    }
+
+    let _ = bytes.split_to(body_start);
+
+    let Some(content_length) = content_length else {
+        return Ok(MessageBody::default());
+    };
+
+    Ok(MessageBody {
+        data: Some(bytes.split_to(content_length.min(available))),
+        truncated: available < content_length,
+        ..Default::default()
+    })
+}
+
+/// Checks if the response cannot have a body: responses to HEAD requests, informational
+/// (1xx), 204 No Content and 304 Not Modified responses.
+fn has_no_body(request_method: &str, code: u16) -> bool {
+    request_method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&code)
+        || code == 204
+        || code == 304
+        || (request_method.eq_ignore_ascii_case("CONNECT") && (200..300).contains(&code))
+}
+
+/// Checks if the response opens a tunnel, after a successful CONNECT the connection
+/// carries the bytes of the tunnel.
+fn is_tunnel(request_method: &str, resp: &Resp) -> bool {
+    request_method.eq_ignore_ascii_case("CONNECT") && (200..300).contains(&resp.code)
+}
+
+/// Checks if the response switches the connection to the protocol.
+fn is_upgrade_to(resp: &Resp, protocol: &str) -> bool {
+    resp.headers
+        .get("upgrade")
+        .is_some_and(|upgrade| upgrade.trim().eq_ignore_ascii_case(protocol))
+}
+
+/// Checks if the response is an interim one, the final response to the request is yet
+/// to come. 101 Switching Protocols is final, the connection is not HTTP/1 after that.
+fn is_interim(resp: &Resp) -> bool {
+    (100..200).contains(&resp.code) && resp.code != 101
+}
+
 /// Parse the request headers and request body as well. Advance the `BytesMut` buffer
-/// according to Content-Length header.
-fn parse_request(req_bytes: &mut BytesMut) -> Result<Req, Box<dyn std::error::Error>> {
-    let mut headers = [httparse::EMPTY_HEADER; 16];
+/// according to Content-Length header or the chunks of the body. If `eof` is set, no more
+/// bytes follow, so a shorter body is accepted as a truncated one.
+fn parse_request(req_bytes: &mut BytesMut, eof: bool) -> Result<Req, ParseError> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers(req_bytes)];
     let mut parsed_req = httparse::Request::new(&mut headers);
     let res = parsed_req.parse(req_bytes)?;
 
     if res.is_partial() {
-        return Err("Partial request".into());
+        return Err(ParseError::Incomplete("Partial request"));
     }
 
+    let target = parsed_req.path.unwrap();
+    let (authority, path) = match split_absolute_form(target) {
+        Some((authority, path)) => (Some(authority), path),
+        None => (None, target.to_string()),
+    };
+
     let mut req = Req {
         method: parsed_req.method.unwrap().to_string(),
-        path: parsed_req.path.unwrap().to_string(),
+        path,
+        authority,
         version: parsed_req.version.unwrap().to_string(),
-        headers: HashMap::new(),
+        headers: Headers::default(),
         body: None,
+        trailers: Headers::default(),
+        truncated: false,
     };
 
     for header in parsed_req.headers {
-        req.headers.insert(
+        req.headers.push(
             header.name.to_string(),
-            String::from_utf8(header.value.to_vec()).unwrap(),
+            String::from_utf8_lossy(header.value).to_string(),
         );
     }
 
-    let body_start = res.unwrap();
+    let body = take_body(req_bytes, res.unwrap(), &req.headers, false, eof)?;
 
-    let _ = req_bytes.split_to(body_start);
+    if let Some(data) = body.data.filter(|data| !data.is_empty()) {
+        req.body = Some(decode_body(&req.headers, &data));
+    }
 
-    if let Some(content_length) = get_content_length(&req.headers) {
-        if content_length > 0 {
-            let body_buf = req_bytes.split_to(content_length);
+    req.trailers = body.trailers;
+    req.truncated = body.truncated;
 
-            let body =
-                String::from_utf8(body_buf.to_vec()).unwrap_or("Body encoding error".to_string());
+    Ok(req)
+}
 
-            req.body = Some(body);
-        }
+/// Split an absolute-form request target, like `http://host:8080/path?q`, into the
+/// authority and the path. Proxies get requests with absolute-form targets.
+fn split_absolute_form(target: &str) -> Option<(String, String)> {
+    let (scheme, rest) = target.split_once("://")?;
+
+    if scheme.is_empty()
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return None;
     }
 
-    Ok(req)
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    // The user info is not part of the host
+    let authority = rest[..end].rsplit('@').next().unwrap_or_default();
+    let path = match &rest[end..] {
+        "" => "/".to_string(),
+        path if !path.starts_with('/') => format!("/{path}"),
+        path => path.to_string(),
+    };
+
+    Some((authority.to_string(), path))
+}
+
+/// Parse the response to a request with `request_method`, the method matters in finding out
+/// if there is a body.
+fn parse_response(
+    resp_bytes: &mut BytesMut,
+    eof: bool,
+    request_method: &str,
+) -> Result<Resp, ParseError> {
+    let (mut resp, head_len) = parse_response_head(resp_bytes)?;
+
+    let body = if has_no_body(request_method, resp.code) {
+        let _ = resp_bytes.split_to(head_len);
+
+        MessageBody::default()
+    } else {
+        take_body(resp_bytes, head_len, &resp.headers, true, eof)?
+    };
+
+    resp.trailers = body.trailers;
+    resp.truncated = body.truncated;
+    resp.close_delimited = body.close_delimited;
+
+    if let Some(data) = body.data {
+        resp.body = Some(decode_body(&resp.headers, &data));
+    }
+
+    Ok(resp)
 }
 
-fn parse_response(resp_bytes: &mut BytesMut) -> Result<Resp, Box<dyn std::error::Error>> {
-    let mut headers = [httparse::EMPTY_HEADER; 16];
+/// Parse the status line and the headers of the response. Gives back the response without
+/// body and the length of the head.
+fn parse_response_head(resp_bytes: &[u8]) -> Result<(Resp, usize), ParseError> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers(resp_bytes)];
     let mut parsed_resp = httparse::Response::new(&mut headers);
-    let res = parsed_resp.parse(resp_bytes).unwrap();
+    let res = parsed_resp.parse(resp_bytes)?;
 
     if res.is_partial() {
-        return Err("Partial response".into());
+        return Err(ParseError::Incomplete("Partial response"));
     }
 
     let mut resp = Resp {
         version: parsed_resp.version.unwrap().to_string(),
         code: parsed_resp.code.unwrap(),
         reason: parsed_resp.reason.map(|r| r.to_string()),
-        headers: HashMap::new(),
+        headers: Headers::default(),
         body: None,
+        trailers: Headers::default(),
+        truncated: false,
+        close_delimited: false,
     };
 
     for header in parsed_resp.headers {
-        resp.headers.insert(
+        resp.headers.push(
             header.name.to_string(),
-            String::from_utf8(header.value.to_vec())?,
+            String::from_utf8_lossy(header.value).to_string(),
         );
     }
 
-    let body_start = res.unwrap();
+    Ok((resp, res.unwrap()))
+}
+
+/// Tells from the payload if it is sent by the client or the server of an HTTP connection.
+/// Gives back `None` if the payload doesn't start with an HTTP message or a TLS hello.
+pub fn sent_by_client(payload: &[u8]) -> Option<bool> {
+    if is_request_start(payload) {
+        Some(true)
+    } else if is_response_start(payload) {
+        Some(false)
+    } else {
+        tls::hello_sender(payload)
+    }
+}
 
-    let _ = resp_bytes.split_to(body_start);
+/// Checks if the bytes start with a request line.
+fn is_request_start(bytes: &[u8]) -> bool {
+    const METHODS: [&[u8]; 9] = [
+        b"GET ",
+        b"POST ",
+        b"PUT ",
+        b"DELETE ",
+        b"HEAD ",
+        b"OPTIONS ",
+        b"PATCH ",
+        b"CONNECT ",
+        b"TRACE ",
+    ];
 
-    if let Some(content_length) = get_content_length(&resp.headers) {
-        let body_buf = resp_bytes.split_to(content_length);
+    METHODS.iter().any(|m| bytes.starts_with(m))
+}
 
-        if let Some(enc) = resp.headers.get("Content-Encoding") {
-            if enc == "gzip" {
-                resp.body = Some(unzip_content(&body_buf)?);
-            } else {
-                return Err(format!("Unknown encoding {enc}").into());
+/// Checks if the bytes start with a status line.
+fn is_response_start(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"HTTP/1.")
+}
+
+/// Get the length from the Content-Length headers. The header can be repeated, or it can
+/// be a list, but all the values need to be the same.
+fn get_content_length(headers: &Headers) -> Result<Option<usize>, String> {
+    let mut length = None;
+
+    for value in headers.get_all("content-length") {
+        for v in value.split(',') {
+            let n =
+                usize::from_str(v.trim()).map_err(|_| format!("Invalid Content-Length {value}"))?;
+
+            if length.is_some_and(|length| length != n) {
+                return Err("Conflicting Content-Length headers".to_string());
             }
-        } else {
-            resp.body =
-                Some(String::from_utf8(body_buf.to_vec()).unwrap_or("Encoding error".to_string()));
+
+            length = Some(n);
         }
     }
 
-    Ok(resp)
+    Ok(length)
 }
 
-fn get_content_length(headers: &HashMap<String, String>) -> Option<usize> {
-    for (k, v) in headers {
-        if "content-length" == k.to_lowercase() {
-            return usize::from_str(v).ok();
+/// Decode the body according to the content codings of the message. If it cannot be done,
+/// the body is kept as it was sent. The fields of form bodies are parsed, too.
+fn decode_body(headers: &Headers, data: &[u8]) -> Body {
+    let encoding = headers
+        .get("content-encoding")
+        .map(|enc| enc.trim())
+        .filter(|enc| !enc.is_empty() && !enc.eq_ignore_ascii_case("identity"));
+
+    let mut body = Body {
+        data: data.to_vec(),
+        wire_size: data.len(),
+        encoding: encoding.map(|enc| enc.to_string()),
+        decode_error: None,
+        form: None,
+        charset: declared_charset(headers),
+    };
+
+    if let Some(encoding) = encoding {
+        match encoding::decode(encoding, data) {
+            Ok(decoded) => body.data = decoded,
+            Err(e) => body.decode_error = Some(e),
         }
     }
 
-    None
+    if body.decode_error.is_none() {
+        body.form = form::parse(headers, &body.data);
+    }
+
+    body
+}
+
+/// Split a header value with parameters, like `form-data; name="file"; filename="a.txt"`,
+/// into its first item and the parameters. The parameter names are lowercase, the quoted
+/// values are unquoted.
+fn header_params(value: &str) -> (&str, Vec<(String, String)>) {
+    let (first, mut rest) = value.split_once(';').unwrap_or((value, ""));
+    let mut params = vec![];
+
+    while let Some((name, tail)) = rest.split_once('=') {
+        let tail = tail.trim_start();
+        let (value, tail) = match tail.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+
+                let tail = &quoted[end..];
+
+                (value, tail.split_once(';').map_or("", |(_, tail)| tail))
+            }
+            None => {
+                let (value, tail) = tail.split_once(';').unwrap_or((tail, ""));
+
+                (value.trim().to_string(), tail)
+            }
+        };
+
+        params.push((name.trim().to_ascii_lowercase(), value));
+        rest = tail;
+    }
+
+    (first.trim(), params)
+}
+
+/// The `charset` parameter of the Content-Type header, like `ISO-8859-1`.
+fn declared_charset(headers: &Headers) -> Option<String> {
+    let (_, params) = header_params(headers.get("content-type")?);
+
+    params
+        .into_iter()
+        .find(|(name, _)| name == "charset")
+        .map(|(_, charset)| charset)
+}
+
+/// Decode the %XX escapes of a string, the result is taken as UTF-8.
+fn percent_decode(s: &str) -> String {
+    String::from_utf8_lossy(&percent_decode_bytes(s.as_bytes())).to_string()
 }
 
-fn unzip_content(buf: &[u8]) -> Result<String, std::io::Error> {
-    let mut gz = MultiGzDecoder::new(buf);
-    let mut s = String::new();
+/// Decode the %XX escapes, the bytes are left for the caller to decode with their charset.
+fn percent_decode_bytes(s: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match hex {
+            Some(decoded) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+
+    bytes
+}
 
-    gz.read_to_string(&mut s)?;
+/// The number of header fields is not limited, but there cannot be more than the lines of
+/// the header section.
+fn max_headers(bytes: &[u8]) -> usize {
+    bytes
+        .split(|b| *b == b'\n')
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .count()
+}
 
-    Ok(s)
+/// Checks if chunked is the last transfer coding of the message.
+fn is_chunked(headers: &Headers) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .last()
+        .and_then(|codings| codings.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
 }
@@ -0,0 +1,822 @@
+// Decoding protobuf messages with the message types of a FileDescriptorSet, or without them
+
+use std::{collections::HashMap, fs, path::Path};
+
+use log::info;
+
+/// Nested messages are not decoded deeper than this.
+const MAX_DEPTH: usize = 64;
+
+// The field types of FieldDescriptorProto
+const TYPE_DOUBLE: u64 = 1;
+const TYPE_FLOAT: u64 = 2;
+const TYPE_INT64: u64 = 3;
+const TYPE_UINT64: u64 = 4;
+const TYPE_INT32: u64 = 5;
+const TYPE_FIXED64: u64 = 6;
+const TYPE_FIXED32: u64 = 7;
+const TYPE_BOOL: u64 = 8;
+const TYPE_STRING: u64 = 9;
+const TYPE_GROUP: u64 = 10;
+const TYPE_MESSAGE: u64 = 11;
+const TYPE_BYTES: u64 = 12;
+const TYPE_UINT32: u64 = 13;
+const TYPE_ENUM: u64 = 14;
+const TYPE_SFIXED32: u64 = 15;
+const TYPE_SFIXED64: u64 = 16;
+const TYPE_SINT32: u64 = 17;
+const TYPE_SINT64: u64 = 18;
+
+const LABEL_REPEATED: u64 = 3;
+
+/// A field value in the wire format.
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// The message types, enums and services of a FileDescriptorSet, the file written by
+/// `protoc --descriptor_set_out`.
+#[derive(Debug, Default)]
+pub struct Schema {
+    /// The message types by their full name, like `package.Message.Nested`.
+    messages: HashMap<String, MessageType>,
+    /// The names of the enum values by the full name of the enum.
+    enums: HashMap<String, HashMap<i32, String>>,
+    /// The request and response types of the methods by their path, like
+    /// `/package.Service/Method`.
+    methods: HashMap<String, (String, String)>,
+}
+
+#[derive(Debug, Default)]
+struct MessageType {
+    fields: Vec<Field>,
+    /// The generated entry type of a map field, with a key (1) and a value (2) field.
+    map_entry: bool,
+}
+
+#[derive(Debug, Default)]
+struct Field {
+    number: u32,
+    json_name: String,
+    repeated: bool,
+    kind: u64,
+    /// The full name of the message or enum type of the field.
+    type_name: String,
+}
+
+/// A JSON value, the fields of the objects are kept in order.
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Schema {
+    pub fn load(path: &Path) -> Result<Schema, String> {
+        let data = fs::read(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+        let invalid = |e| format!("Invalid descriptor set {}: {e}", path.display());
+        let mut schema = Schema::default();
+
+        for (number, value) in parse_fields(&data).map_err(invalid)? {
+            if let (1, Value::Bytes(file)) = (number, value) {
+                schema.add_file(file).map_err(invalid)?;
+            }
+        }
+
+        if schema.messages.is_empty() {
+            return Err(format!("No message types in {}", path.display()));
+        }
+
+        info!(
+            "Loaded {} message types and {} methods from {}",
+            schema.messages.len(),
+            schema.methods.len(),
+            path.display()
+        );
+
+        Ok(schema)
+    }
+
+    /// The request and response types of the method called on the path.
+    pub fn method(&self, path: &str) -> Option<(&str, &str)> {
+        self.methods
+            .get(path)
+            .map(|(input, output)| (input.as_str(), output.as_str()))
+    }
+
+    /// Decode the message with its type to JSON, following the mapping of proto3. The
+    /// fields which are not in the message type are shown with their numbers.
+    pub fn to_json(&self, type_name: &str, data: &[u8]) -> Result<String, String> {
+        let json = self.message_json(type_name, data, 0)?;
+        let mut out = String::new();
+
+        write_json(&mut out, &json, 0);
+
+        Ok(out)
+    }
+
+    fn add_file(&mut self, data: &[u8]) -> Result<(), String> {
+        let fields = parse_fields(data)?;
+        let package = string_field(&fields, 2).unwrap_or_default();
+
+        for (number, value) in fields {
+            match (number, value) {
+                (4, Value::Bytes(message)) => self.add_message(&package, message)?,
+                (5, Value::Bytes(enum_type)) => self.add_enum(&package, enum_type)?,
+                (6, Value::Bytes(service)) => self.add_service(&package, service)?,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_message(&mut self, scope: &str, data: &[u8]) -> Result<(), String> {
+        let fields = parse_fields(data)?;
+        let name = full_name(scope, &string_field(&fields, 1).unwrap_or_default());
+        let mut message = MessageType::default();
+
+        for (number, value) in fields {
+            match (number, value) {
+                (2, Value::Bytes(field)) => message.fields.push(parse_field(field)?),
+                (3, Value::Bytes(nested)) => self.add_message(&name, nested)?,
+                (4, Value::Bytes(enum_type)) => self.add_enum(&name, enum_type)?,
+                (7, Value::Bytes(options)) => {
+                    message.map_entry = varint_field(&parse_fields(options)?, 7) == Some(1);
+                }
+                _ => {}
+            }
+        }
+
+        self.messages.insert(name, message);
+
+        Ok(())
+    }
+
+    fn add_enum(&mut self, scope: &str, data: &[u8]) -> Result<(), String> {
+        let fields = parse_fields(data)?;
+        let name = full_name(scope, &string_field(&fields, 1).unwrap_or_default());
+        let mut values = HashMap::new();
+
+        for (number, value) in fields {
+            if let (2, Value::Bytes(value)) = (number, value) {
+                let value = parse_fields(value)?;
+
+                values.insert(
+                    varint_field(&value, 2).unwrap_or_default() as i32,
+                    string_field(&value, 1).unwrap_or_default(),
+                );
+            }
+        }
+
+        self.enums.insert(name, values);
+
+        Ok(())
+    }
+
+    fn add_service(&mut self, package: &str, data: &[u8]) -> Result<(), String> {
+        let fields = parse_fields(data)?;
+        let name = full_name(package, &string_field(&fields, 1).unwrap_or_default());
+
+        for (number, value) in fields {
+            if let (2, Value::Bytes(method)) = (number, value) {
+                let method = parse_fields(method)?;
+                let path = format!("/{name}/{}", string_field(&method, 1).unwrap_or_default());
+                let input = type_name(string_field(&method, 2));
+                let output = type_name(string_field(&method, 3));
+
+                self.methods.insert(path, (input, output));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn message_json(&self, type_name: &str, data: &[u8], depth: usize) -> Result<Json, String> {
+        let message = self
+            .messages
+            .get(type_name)
+            .ok_or_else(|| format!("Unknown message type {type_name}"))?;
+
+        if depth > MAX_DEPTH {
+            return Err("Messages are nested too deep".to_string());
+        }
+
+        let mut values: Vec<Vec<Json>> = message.fields.iter().map(|_| vec![]).collect();
+        let mut unknown = vec![];
+
+        for (number, value) in parse_fields(data)? {
+            match message.fields.iter().position(|f| f.number == number) {
+                Some(i) => self.field_json(&message.fields[i], value, depth, &mut values[i])?,
+                None => unknown.push((number.to_string(), raw_json(value))),
+            }
+        }
+
+        let mut object = vec![];
+
+        for (field, mut values) in message.fields.iter().zip(values) {
+            let json = if values.is_empty() {
+                continue;
+            } else if self.is_map(field) {
+                Json::Object(values.into_iter().map(map_entry).collect())
+            } else if field.repeated {
+                Json::Array(values)
+            } else {
+                // The last value wins
+                values.pop().unwrap_or(Json::Null)
+            };
+
+            object.push((field.json_name.clone(), json));
+        }
+
+        object.extend(unknown);
+
+        Ok(Json::Object(object))
+    }
+
+    fn field_json(
+        &self,
+        field: &Field,
+        value: Value,
+        depth: usize,
+        values: &mut Vec<Json>,
+    ) -> Result<(), String> {
+        let json = match (field.kind, value) {
+            (TYPE_MESSAGE, Value::Bytes(bytes)) => {
+                self.message_json(&field.type_name, bytes, depth + 1)?
+            }
+            (TYPE_STRING, Value::Bytes(bytes)) => {
+                Json::String(String::from_utf8_lossy(bytes).to_string())
+            }
+            (TYPE_BYTES, Value::Bytes(bytes)) => Json::String(base64(bytes)),
+            (TYPE_GROUP, _) => return Err("Groups are not supported".to_string()),
+            (kind, Value::Bytes(bytes)) => {
+                // Packed repeated scalars
+                let mut pos = 0;
+
+                while pos < bytes.len() {
+                    let value = match wire_type(kind) {
+                        1 => Value::Fixed64(read_fixed64(bytes, &mut pos)?),
+                        5 => Value::Fixed32(read_fixed32(bytes, &mut pos)?),
+                        _ => Value::Varint(read_varint(bytes, &mut pos)?),
+                    };
+
+                    values.push(self.scalar_json(field, value)?);
+                }
+
+                return Ok(());
+            }
+            _ => self.scalar_json(field, value)?,
+        };
+
+        values.push(json);
+
+        Ok(())
+    }
+
+    /// Convert the numeric values, 64 bit integers are strings in JSON.
+    fn scalar_json(&self, field: &Field, value: Value) -> Result<Json, String> {
+        let number = |n: &dyn ToString| Json::Number(n.to_string());
+        let string = |n: &dyn ToString| Json::String(n.to_string());
+
+        let json = match (field.kind, value) {
+            (TYPE_DOUBLE, Value::Fixed64(v)) => float_json(f64::from_bits(v)),
+            (TYPE_FLOAT, Value::Fixed32(v)) => float_json(f32::from_bits(v) as f64),
+            (TYPE_INT64, Value::Varint(v)) => string(&(v as i64)),
+            (TYPE_UINT64, Value::Varint(v)) => string(&v),
+            (TYPE_INT32, Value::Varint(v)) => number(&(v as i32)),
+            (TYPE_FIXED64, Value::Fixed64(v)) => string(&v),
+            (TYPE_FIXED32, Value::Fixed32(v)) => number(&v),
+            (TYPE_BOOL, Value::Varint(v)) => Json::Bool(v != 0),
+            (TYPE_UINT32, Value::Varint(v)) => number(&(v as u32)),
+            (TYPE_ENUM, Value::Varint(v)) => {
+                let name = self
+                    .enums
+                    .get(&field.type_name)
+                    .and_then(|values| values.get(&(v as i32)));
+
+                match name {
+                    Some(name) => Json::String(name.clone()),
+                    None => number(&(v as i32)),
+                }
+            }
+            (TYPE_SFIXED32, Value::Fixed32(v)) => number(&(v as i32)),
+            (TYPE_SFIXED64, Value::Fixed64(v)) => string(&(v as i64)),
+            (TYPE_SINT32, Value::Varint(v)) => number(&(zigzag(v) as i32)),
+            (TYPE_SINT64, Value::Varint(v)) => string(&zigzag(v)),
+            _ => {
+                return Err(format!(
+                    "Field {} ({}) has a wrong wire type",
+                    field.json_name, field.number
+                ))
+            }
+        };
+
+        Ok(json)
+    }
+
+    fn is_map(&self, field: &Field) -> bool {
+        field.repeated
+            && field.kind == TYPE_MESSAGE
+            && self
+                .messages
+                .get(&field.type_name)
+                .is_some_and(|message| message.map_entry)
+    }
+}
+
+/// Show a message without its type, like `protoc --decode_raw`. The length-delimited fields
+/// are shown as text if they are printable, as nested messages if they can be parsed as
+/// one, otherwise as escaped bytes.
+pub fn decode_raw(data: &[u8]) -> Result<String, String> {
+    let fields = parse_fields(data)?;
+    let mut out = String::new();
+
+    write_raw(&mut out, &fields, 0);
+
+    Ok(out)
+}
+
+fn write_raw(out: &mut String, fields: &[(u32, Value)], depth: usize) {
+    let indent = "  ".repeat(depth);
+
+    for (number, value) in fields {
+        match *value {
+            Value::Varint(v) => out.push_str(&format!("{indent}{number}: {v}\n")),
+            Value::Fixed64(v) => out.push_str(&format!("{indent}{number}: 0x{v:016x}\n")),
+            Value::Fixed32(v) => out.push_str(&format!("{indent}{number}: 0x{v:08x}\n")),
+            Value::Bytes(bytes) => {
+                if let Some(text) = printable(bytes) {
+                    out.push_str(&format!("{indent}{number}: {text:?}\n"));
+
+                    continue;
+                }
+
+                let nested = parse_fields(bytes)
+                    .ok()
+                    .filter(|nested| depth < MAX_DEPTH && !nested.is_empty());
+
+                match nested {
+                    Some(nested) => {
+                        out.push_str(&format!("{indent}{number} {{\n"));
+                        write_raw(out, &nested, depth + 1);
+                        out.push_str(&format!("{indent}}}\n"));
+                    }
+                    None => {
+                        out.push_str(&format!("{indent}{number}: \"{}\"\n", bytes.escape_ascii()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read the fields of a message in the wire format.
+fn parse_fields(data: &[u8]) -> Result<Vec<(u32, Value<'_>)>, String> {
+    let mut fields = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let key = read_varint(data, &mut pos)?;
+        let number = key >> 3;
+
+        if number == 0 || number > 0x1fff_ffff {
+            return Err(format!("Invalid field number {number}"));
+        }
+
+        let value = match key & 0x7 {
+            0 => Value::Varint(read_varint(data, &mut pos)?),
+            1 => Value::Fixed64(read_fixed64(data, &mut pos)?),
+            2 => {
+                let len = read_varint(data, &mut pos)?;
+                let len = usize::try_from(len).map_err(|_| format!("Invalid length {len}"))?;
+
+                Value::Bytes(read_bytes(data, &mut pos, len)?)
+            }
+            5 => Value::Fixed32(read_fixed32(data, &mut pos)?),
+            wire_type => {
+                return Err(format!(
+                    "Unsupported wire type {wire_type} of field {number}"
+                ))
+            }
+        };
+
+        fields.push((number as u32, value));
+    }
+
+    Ok(fields)
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value = 0;
+
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or("Truncated varint")?;
+
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err("Varint is too long".to_string())
+}
+
+fn read_fixed64(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut bytes = [0; 8];
+
+    bytes.copy_from_slice(read_bytes(data, pos, 8)?);
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_fixed32(data: &[u8], pos: &mut usize) -> Result<u32, String> {
+    let mut bytes = [0; 4];
+
+    bytes.copy_from_slice(read_bytes(data, pos, 4)?);
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    let end = pos
+        .checked_add(len)
+        .filter(|end| *end <= data.len())
+        .ok_or("Truncated field")?;
+    let bytes = &data[*pos..end];
+
+    *pos = end;
+
+    Ok(bytes)
+}
+
+/// The value of the last length-delimited field with the number as a string.
+fn string_field(fields: &[(u32, Value)], number: u32) -> Option<String> {
+    fields.iter().rev().find_map(|field| match *field {
+        (n, Value::Bytes(bytes)) if n == number => Some(String::from_utf8_lossy(bytes).to_string()),
+        _ => None,
+    })
+}
+
+fn varint_field(fields: &[(u32, Value)], number: u32) -> Option<u64> {
+    fields.iter().rev().find_map(|field| match *field {
+        (n, Value::Varint(v)) if n == number => Some(v),
+        _ => None,
+    })
+}
+
+fn parse_field(data: &[u8]) -> Result<Field, String> {
+    let fields = parse_fields(data)?;
+    let name = string_field(&fields, 1).unwrap_or_default();
+
+    Ok(Field {
+        number: varint_field(&fields, 3).unwrap_or_default() as u32,
+        json_name: string_field(&fields, 10).unwrap_or_else(|| json_name(&name)),
+        repeated: varint_field(&fields, 4) == Some(LABEL_REPEATED),
+        kind: varint_field(&fields, 5).unwrap_or_default(),
+        type_name: type_name(string_field(&fields, 6)),
+    })
+}
+
+fn full_name(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_string()
+    } else {
+        format!("{scope}.{name}")
+    }
+}
+
+/// The type names are fully qualified with a leading dot in the descriptors.
+fn type_name(name: Option<String>) -> String {
+    let name = name.unwrap_or_default();
+
+    name.strip_prefix('.').unwrap_or(&name).to_string()
+}
+
+/// The JSON name of a field is its name in lower camel case, like `user_id` -> `userId`.
+fn json_name(name: &str) -> String {
+    let mut json_name = String::new();
+    let mut upper = false;
+
+    for c in name.chars() {
+        match c {
+            '_' => upper = true,
+            c if upper => {
+                json_name.push(c.to_ascii_uppercase());
+                upper = false;
+            }
+            c => json_name.push(c),
+        }
+    }
+
+    json_name
+}
+
+/// The wire type of the packed values of a scalar type.
+fn wire_type(kind: u64) -> u8 {
+    match kind {
+        TYPE_DOUBLE | TYPE_FIXED64 | TYPE_SFIXED64 => 1,
+        TYPE_FLOAT | TYPE_FIXED32 | TYPE_SFIXED32 => 5,
+        _ => 0,
+    }
+}
+
+fn zigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+fn float_json(v: f64) -> Json {
+    if v.is_nan() {
+        Json::String("NaN".to_string())
+    } else if v.is_infinite() {
+        Json::String(if v > 0.0 { "Infinity" } else { "-Infinity" }.to_string())
+    } else {
+        Json::Number(v.to_string())
+    }
+}
+
+/// The value of a field which is not in the message type.
+fn raw_json(value: Value) -> Json {
+    match value {
+        Value::Varint(v) | Value::Fixed64(v) => Json::Number(v.to_string()),
+        Value::Fixed32(v) => Json::Number(v.to_string()),
+        Value::Bytes(bytes) => match printable(bytes) {
+            Some(text) => Json::String(text.to_string()),
+            None => Json::String(base64(bytes)),
+        },
+    }
+}
+
+/// Map fields are sent as repeated entry messages, the key of the entry becomes the
+/// field name in the object.
+fn map_entry(entry: Json) -> (String, Json) {
+    let Json::Object(fields) = entry else {
+        return (String::new(), entry);
+    };
+
+    let mut key = String::new();
+    let mut value = Json::Null;
+
+    for (name, json) in fields {
+        match (name.as_str(), json) {
+            ("key", Json::String(s) | Json::Number(s)) => key = s,
+            ("key", Json::Bool(b)) => key = b.to_string(),
+            ("value", json) => value = json,
+            _ => {}
+        }
+    }
+
+    (key, value)
+}
+
+fn write_json(out: &mut String, json: &Json, depth: usize) {
+    let indent = |out: &mut String, depth| out.push_str(&"  ".repeat(depth));
+
+    match json {
+        Json::Null => out.push_str("null"),
+        Json::Bool(b) => out.push_str(&b.to_string()),
+        Json::Number(n) => out.push_str(n),
+        Json::String(s) => write_json_string(out, s),
+        Json::Array(items) if items.is_empty() => out.push_str("[]"),
+        Json::Array(items) => {
+            out.push_str("[\n");
+
+            for (i, item) in items.iter().enumerate() {
+                indent(out, depth + 1);
+                write_json(out, item, depth + 1);
+                out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+            }
+
+            indent(out, depth);
+            out.push(']');
+        }
+        Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+        Json::Object(fields) => {
+            out.push_str("{\n");
+
+            for (i, (name, value)) in fields.iter().enumerate() {
+                indent(out, depth + 1);
+                write_json_string(out, name);
+                out.push_str(": ");
+                write_json(out, value, depth + 1);
+                out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+            }
+
+            indent(out, depth);
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+fn printable(bytes: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(bytes).ok()?;
+
+    text.chars()
+        .all(|c| !c.is_control() || c.is_whitespace())
+        .then_some(text)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut v: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        while v >= 0x80 {
+            bytes.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+
+        bytes.push(v as u8);
+        bytes
+    }
+
+    fn int_field(number: u32, v: u64) -> Vec<u8> {
+        let mut field = varint((number as u64) << 3);
+
+        field.extend(varint(v));
+        field
+    }
+
+    fn bytes_field(number: u32, bytes: &[u8]) -> Vec<u8> {
+        let mut field = varint((number as u64) << 3 | 2);
+
+        field.extend(varint(bytes.len() as u64));
+        field.extend_from_slice(bytes);
+        field
+    }
+
+    fn field(name: &str, number: u64, label: u64, kind: u64, type_name: &str) -> Vec<u8> {
+        [
+            bytes_field(1, name.as_bytes()),
+            int_field(3, number),
+            int_field(4, label),
+            int_field(5, kind),
+            bytes_field(6, type_name.as_bytes()),
+        ]
+        .concat()
+    }
+
+    /// The descriptor of `package demo` with the message type `User`, the enum `Status` and
+    /// the service `Users`.
+    fn schema() -> Schema {
+        let entry = [
+            bytes_field(1, b"CountsEntry"),
+            bytes_field(2, &field("key", 1, 1, TYPE_STRING, "")),
+            bytes_field(2, &field("value", 2, 1, TYPE_INT64, "")),
+            bytes_field(7, &int_field(7, 1)),
+        ]
+        .concat();
+        let user = [
+            bytes_field(1, b"User"),
+            bytes_field(2, &field("user_name", 1, 1, TYPE_STRING, "")),
+            bytes_field(2, &field("delta", 2, 1, TYPE_SINT32, "")),
+            bytes_field(2, &field("ids", 3, LABEL_REPEATED, TYPE_INT32, "")),
+            bytes_field(2, &field("status", 4, 1, TYPE_ENUM, ".demo.Status")),
+            bytes_field(
+                2,
+                &field(
+                    "counts",
+                    5,
+                    LABEL_REPEATED,
+                    TYPE_MESSAGE,
+                    ".demo.User.CountsEntry",
+                ),
+            ),
+            bytes_field(3, &entry),
+        ]
+        .concat();
+        let status = [
+            bytes_field(1, b"Status"),
+            bytes_field(2, &[bytes_field(1, b"UNKNOWN"), int_field(2, 0)].concat()),
+            bytes_field(2, &[bytes_field(1, b"ACTIVE"), int_field(2, 1)].concat()),
+        ]
+        .concat();
+        let method = [
+            bytes_field(1, b"Get"),
+            bytes_field(2, b".demo.User"),
+            bytes_field(3, b".demo.User"),
+        ]
+        .concat();
+        let file = [
+            bytes_field(2, b"demo"),
+            bytes_field(4, &user),
+            bytes_field(5, &status),
+            bytes_field(
+                6,
+                &[bytes_field(1, b"Users"), bytes_field(2, &method)].concat(),
+            ),
+        ]
+        .concat();
+
+        let mut schema = Schema::default();
+
+        schema.add_file(&file).unwrap();
+        schema
+    }
+
+    #[test]
+    fn message_to_json() {
+        let schema = schema();
+        let message = [
+            bytes_field(1, b"ann"),
+            int_field(2, 3),
+            bytes_field(3, &[1, 0xac, 0x02]),
+            int_field(4, 1),
+            bytes_field(5, &[bytes_field(1, b"a"), int_field(2, 5)].concat()),
+            int_field(9, 7),
+        ]
+        .concat();
+
+        assert_eq!(
+            schema.method("/demo.Users/Get"),
+            Some(("demo.User", "demo.User"))
+        );
+        assert_eq!(
+            schema.to_json("demo.User", &message).unwrap(),
+            "{\n  \"userName\": \"ann\",\n  \"delta\": -2,\n  \"ids\": [\n    1,\n    300\n  ],\n  \
+             \"status\": \"ACTIVE\",\n  \"counts\": {\n    \"a\": \"5\"\n  },\n  \"9\": 7\n}"
+        );
+        assert_eq!(
+            schema.to_json("demo.User", &int_field(1, 1)).unwrap_err(),
+            "Field userName (1) has a wrong wire type"
+        );
+        assert!(schema.to_json("demo.Missing", &[]).is_err());
+    }
+
+    #[test]
+    fn raw_message() {
+        let message = [
+            bytes_field(1, b"abc"),
+            bytes_field(2, &int_field(1, 1)),
+            vec![(3 << 3) | 5, 1, 0, 0, 0],
+        ]
+        .concat();
+
+        assert_eq!(
+            decode_raw(&message).unwrap(),
+            "1: \"abc\"\n2 {\n  1: 1\n}\n3: 0x00000001\n"
+        );
+        assert_eq!(decode_raw(&[0x08]).unwrap_err(), "Truncated varint");
+        assert_eq!(
+            decode_raw(&[0x0a, 0x05, b'a']).unwrap_err(),
+            "Truncated field"
+        );
+        assert_eq!(
+            decode_raw(&[0x0b]).unwrap_err(),
+            "Unsupported wire type 3 of field 1"
+        );
+        assert_eq!(decode_raw(&[0x00]).unwrap_err(), "Invalid field number 0");
+    }
+}
@@ -0,0 +1,470 @@
+// Reassembling one direction of a tcp connection by the sequence numbers
+
+use std::collections::{BTreeMap, VecDeque};
+
+use bytes::BytesMut;
+
+/// The maximum number of out of order bytes waiting for the missing segments. Over this
+/// the missing bytes are considered lost.
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+
+/// Bytes which are missing from the capture. The offset is relative to the beginning of
+/// the byte stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gap {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The reassembled bytes of one direction of a tcp connection.
+///
+/// Segments are put in order by their sequence numbers, retransmitted and overlapping bytes
+/// are dropped. Bytes never seen in the capture are skipped and recorded as gaps, so the
+/// parser will not parse messages across them.
+#[derive(Default)]
+pub struct ByteStream {
+    /// The sequence number of the byte at offset 0, it is known after the first segment.
+    base_seq: Option<u32>,
+    /// The offset of the next byte expected, the bytes before are delivered to the buffer
+    /// or they are skipped as gaps.
+    delivered: u64,
+    /// Out of order segments by their offsets.
+    pending: BTreeMap<u64, Vec<u8>>,
+    pending_bytes: usize,
+    /// Reassembled bytes which are not consumed by the parser yet.
+    buffer: BytesMut,
+    /// The offset of the first byte of the buffer.
+    offset: u64,
+    /// Gaps which are not passed by the parser yet.
+    gaps: VecDeque<Gap>,
+    /// Bytes missing from the capture which are skipped before the next message.
+    skipped: u64,
+    /// No more bytes are coming, the end of the buffer is the end of the stream.
+    closed: bool,
+}
+
+impl ByteStream {
+    /// Add a tcp segment of this direction.
+    pub fn segment(&mut self, seq: u32, syn: bool, payload: &[u8]) {
+        // SYN takes one sequence number, data starts after that
+        let data_seq = if syn { seq.wrapping_add(1) } else { seq };
+
+        if syn && self.base_seq.is_none() {
+            self.base_seq = Some(data_seq);
+        }
+
+        if payload.is_empty() {
+            return;
+        }
+
+        let base_seq = *self.base_seq.get_or_insert(data_seq);
+        let next_seq = base_seq.wrapping_add(self.delivered as u32);
+        let distance = data_seq.wrapping_sub(next_seq) as i32 as i64;
+        let start = self.delivered as i64 + distance;
+        let end = start + payload.len() as i64;
+
+        if end <= self.delivered as i64 {
+            // Retransmission of bytes we already have
+            return;
+        }
+
+        if start <= self.delivered as i64 {
+            let overlap = (self.delivered as i64 - start) as usize;
+
+            self.deliver(&payload[overlap..]);
+            self.deliver_pending();
+        } else {
+            self.store_pending(start as u64, payload);
+
+            if self.pending_bytes > MAX_PENDING_BYTES {
+                self.skip_to_pending();
+            }
+        }
+    }
+
+    /// The other side acknowledged the bytes up to `ack`. If we have out of order segments
+    /// after an acknowledged hole, the hole was lost from the capture, it won't be
+    /// retransmitted.
+    pub fn acknowledged(&mut self, ack: u32) {
+        let Some(base_seq) = self.base_seq else {
+            return;
+        };
+
+        let next_seq = base_seq.wrapping_add(self.delivered as u32);
+        let distance = ack.wrapping_sub(next_seq) as i32;
+
+        if distance <= 0 {
+            return;
+        }
+
+        let acked = self.delivered + distance as u64;
+
+        while let Some(first) = self.pending.keys().next().copied() {
+            if first >= acked {
+                break;
+            }
+
+            self.skip_to_pending();
+        }
+    }
+
+    /// The connection is over, the missing bytes won't arrive.
+    pub fn close(&mut self) {
+        while !self.pending.is_empty() {
+            self.skip_to_pending();
+        }
+
+        self.closed = true;
+    }
+
+    /// Parse the next message with `parse` from the reassembled bytes, but never across a
+    /// gap. `parse` gets the bytes up to the next gap (or to the end of the stream) and a
+    /// flag telling that no more bytes follow them. It needs to consume the bytes of the
+    /// message it parsed, and nothing if it fails. If the message is cut by a gap, after the
+    /// gap the bytes are dropped till the first position where `is_message_start` finds a
+    /// new message.
+    ///
+    /// Gives back the message and the number of bytes missing from the capture, which
+    /// belongs to the message. Gaps skipped by a failed parse are counted for the next
+    /// message.
+    pub fn parse_message<T, E>(
+        &mut self,
+        mut parse: impl FnMut(&mut BytesMut, bool) -> Result<T, E>,
+        is_message_start: fn(&[u8]) -> bool,
+    ) -> Result<(T, u64), E> {
+        loop {
+            let gap = self.gaps.front().copied();
+            let limit = match gap {
+                Some(gap) => (gap.offset - self.offset) as usize,
+                None => self.buffer.len(),
+            };
+
+            let mut bytes = self.buffer.split_to(limit);
+            let mut result = parse(&mut bytes, gap.is_none() && self.closed);
+            let mut cut = false;
+
+            if result.is_err() && gap.is_some() {
+                // Maybe the message is complete if we accept that it ends at the gap
+                result = parse(&mut bytes, true);
+                cut = true;
+            }
+
+            self.offset += (limit - bytes.len()) as u64;
+
+            match result {
+                Ok(message) => {
+                    self.restore(bytes);
+
+                    let mut missing = std::mem::take(&mut self.skipped);
+
+                    if cut {
+                        missing += self.skip_gap(is_message_start);
+                    }
+
+                    return Ok((message, missing));
+                }
+                Err(e) => {
+                    if gap.is_none() {
+                        self.restore(bytes);
+
+                        return Err(e);
+                    }
+
+                    // The beginning of the message is before the gap, it is lost
+                    self.offset += bytes.len() as u64;
+                    self.skipped += self.skip_gap(is_message_start);
+                }
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Bytes are missing from the capture after the ones received so far.
+    pub fn has_gap(&self) -> bool {
+        !self.gaps.is_empty()
+    }
+
+    /// Append bytes which are not sent in tcp segments, like decrypted application data.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.deliver(bytes);
+    }
+
+    /// The bytes received so far, up to the next gap.
+    pub fn peek(&self) -> &[u8] {
+        let limit = match self.gaps.front() {
+            Some(gap) => (gap.offset - self.offset) as usize,
+            None => self.buffer.len(),
+        };
+
+        &self.buffer[..limit]
+    }
+
+    /// Drop every byte received so far, they cannot be parsed anymore. Gives back the
+    /// number of bytes dropped.
+    pub fn discard(&mut self) -> usize {
+        let len = self.buffer.len();
+
+        self.offset = self.delivered;
+        self.buffer.clear();
+        self.gaps.clear();
+        self.skipped = 0;
+
+        len
+    }
+
+    /// Skip the bytes of a message which cannot be parsed, till the start of the next
+    /// message. If the next message is not found, the bytes are skipped only if no more
+    /// bytes belong to the message: the stream is closed or a gap follows. Gives back the
+    /// skipped bytes, or `None` if nothing is skipped.
+    pub fn skip_message(&mut self, is_message_start: fn(&[u8]) -> bool) -> Option<Vec<u8>> {
+        let gap = self.gaps.front().copied();
+        let limit = match gap {
+            Some(gap) => (gap.offset - self.offset) as usize,
+            None => self.buffer.len(),
+        };
+
+        if limit == 0 {
+            return None;
+        }
+
+        let end = match (1..limit).find(|i| is_message_start(&self.buffer[*i..limit])) {
+            Some(start) => start,
+            None if self.closed || gap.is_some() => limit,
+            None => return None,
+        };
+
+        self.offset += end as u64;
+
+        Some(self.buffer.split_to(end).to_vec())
+    }
+
+    /// Put back the unconsumed bytes before the buffer.
+    fn restore(&mut self, mut bytes: BytesMut) {
+        bytes.unsplit(std::mem::take(&mut self.buffer));
+
+        self.buffer = bytes;
+    }
+
+    /// Skip the bytes before the next gap, the gap and the bytes after it till the start of
+    /// the next message. Gives back the number of bytes missing from the capture.
+    fn skip_gap(&mut self, is_message_start: fn(&[u8]) -> bool) -> u64 {
+        let Some(gap) = self.gaps.pop_front() else {
+            return 0;
+        };
+
+        let _ = self.buffer.split_to((gap.offset - self.offset) as usize);
+        self.offset = gap.offset + gap.len;
+
+        let limit = match self.gaps.front() {
+            Some(next_gap) => (next_gap.offset - self.offset) as usize,
+            None => self.buffer.len(),
+        };
+
+        let start = (0..limit)
+            .find(|i| is_message_start(&self.buffer[*i..limit]))
+            .unwrap_or(limit);
+
+        let _ = self.buffer.split_to(start);
+        self.offset += start as u64;
+
+        gap.len
+    }
+
+    fn deliver(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+        self.delivered += bytes.len() as u64;
+    }
+
+    fn deliver_pending(&mut self) {
+        while let Some(entry) = self.pending.first_entry() {
+            let start = *entry.key();
+
+            if start > self.delivered {
+                break;
+            }
+
+            let bytes = entry.remove();
+
+            self.pending_bytes -= bytes.len();
+
+            let end = start + bytes.len() as u64;
+
+            if end > self.delivered {
+                let overlap = (self.delivered - start) as usize;
+
+                self.deliver(&bytes[overlap..]);
+            }
+        }
+    }
+
+    fn store_pending(&mut self, start: u64, payload: &[u8]) {
+        match self.pending.get(&start) {
+            Some(existing) if existing.len() >= payload.len() => {}
+            Some(existing) => {
+                self.pending_bytes += payload.len() - existing.len();
+                self.pending.insert(start, payload.to_vec());
+            }
+            None => {
+                self.pending_bytes += payload.len();
+                self.pending.insert(start, payload.to_vec());
+            }
+        }
+    }
+
+    /// Give up waiting for the bytes before the first out of order segment, record them
+    /// as a gap and continue with the segment.
+    fn skip_to_pending(&mut self) {
+        let Some(first) = self.pending.keys().next().copied() else {
+            return;
+        };
+
+        if first > self.delivered {
+            self.gaps.push_back(Gap {
+                offset: self.delivered,
+                len: first - self.delivered,
+            });
+
+            self.delivered = first;
+        }
+
+        self.deliver_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a line ending with a newline, at the end of the stream the rest is a line too.
+    fn parse_line(bytes: &mut BytesMut, eof: bool) -> Result<String, ()> {
+        let end = match bytes.iter().position(|b| *b == b'\n') {
+            Some(pos) => pos + 1,
+            None if eof && !bytes.is_empty() => bytes.len(),
+            None => return Err(()),
+        };
+
+        Ok(String::from_utf8_lossy(&bytes.split_to(end)).to_string())
+    }
+
+    fn is_line_start(bytes: &[u8]) -> bool {
+        bytes.starts_with(b"line")
+    }
+
+    #[test]
+    fn out_of_order_segments() {
+        let mut stream = ByteStream::default();
+
+        stream.segment(1000, true, b"");
+        stream.segment(1011, false, b"!");
+        stream.segment(1006, false, b"world");
+        assert_eq!(stream.peek(), b"");
+
+        stream.segment(1001, false, b"hello");
+        assert_eq!(stream.peek(), b"helloworld!");
+        assert_eq!(stream.pending_bytes, 0);
+    }
+
+    #[test]
+    fn retransmitted_and_overlapping_segments() {
+        let mut stream = ByteStream::default();
+
+        stream.segment(1, false, b"abc");
+        stream.segment(1, false, b"abc");
+        stream.segment(2, false, b"bcdef");
+        stream.segment(10, false, b"jk");
+        stream.segment(10, false, b"jk");
+        stream.segment(10, false, b"j");
+        assert_eq!(stream.pending_bytes, 2);
+
+        stream.segment(6, false, b"fghij");
+        assert_eq!(stream.peek(), b"abcdefghijk");
+        assert_eq!(stream.pending_bytes, 0);
+
+        stream.segment(3, false, b"cdefghijkl");
+        assert_eq!(stream.peek(), b"abcdefghijkl");
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut stream = ByteStream::default();
+
+        stream.segment(u32::MAX - 3, true, b"");
+        stream.segment(u32::MAX - 2, false, b"abc");
+        stream.segment(3, false, b"ghi");
+        stream.segment(0, false, b"def");
+        assert_eq!(stream.peek(), b"abcdefghi");
+
+        // A retransmission from before the wrap
+        stream.segment(u32::MAX - 1, false, b"bcdef");
+        assert_eq!(stream.peek(), b"abcdefghi");
+
+        stream.acknowledged(6);
+        assert!(!stream.has_gap());
+    }
+
+    #[test]
+    fn acknowledged_hole_is_skipped() {
+        let mut stream = ByteStream::default();
+
+        stream.segment(1, false, b"line 1\n");
+        // "line 2\n" is missing, the next segment starts in the middle of a line
+        stream.segment(15, false, b"xx\nline 3\n");
+        assert!(!stream.has_gap());
+
+        stream.acknowledged(25);
+        assert!(stream.has_gap());
+        assert_eq!(stream.peek(), b"line 1\n");
+
+        let (line, missing) = stream.parse_message(parse_line, is_line_start).unwrap();
+
+        assert_eq!((line.as_str(), missing), ("line 1\n", 0));
+
+        let (line, missing) = stream.parse_message(parse_line, is_line_start).unwrap();
+
+        assert_eq!((line.as_str(), missing), ("line 3\n", 7));
+        assert!(!stream.has_gap());
+        assert!(stream.parse_message(parse_line, is_line_start).is_err());
+    }
+
+    #[test]
+    fn message_cut_by_gap() {
+        let mut stream = ByteStream::default();
+
+        stream.segment(1, false, b"line 1 without end");
+        stream.segment(30, false, b"ing\nline 2\n");
+        stream.close();
+
+        let (line, missing) = stream.parse_message(parse_line, is_line_start).unwrap();
+
+        assert_eq!((line.as_str(), missing), ("line 1 without end", 11));
+
+        let (line, missing) = stream.parse_message(parse_line, is_line_start).unwrap();
+
+        assert_eq!((line.as_str(), missing), ("line 2\n", 0));
+    }
+
+    #[test]
+    fn unparsable_bytes_are_skipped() {
+        let mut stream = ByteStream::default();
+
+        stream.segment(1, false, b"garbage line 1\n");
+        assert_eq!(
+            stream.skip_message(is_line_start),
+            Some(b"garbage ".to_vec())
+        );
+        assert_eq!(stream.peek(), b"line 1\n");
+
+        stream.segment(16, false, b"more garbage");
+        stream.parse_message(parse_line, is_line_start).unwrap();
+        assert_eq!(stream.skip_message(is_line_start), None);
+
+        stream.close();
+        assert_eq!(
+            stream.skip_message(is_line_start),
+            Some(b"more garbage".to_vec())
+        );
+    }
+}
@@ -0,0 +1,104 @@
+// Parsing the server-sent events of text/event-stream responses (HTML Living Standard 9.2)
+
+use crate::ui::stream::{Headers, SseEvent};
+
+/// Checks if the response body is a stream of server-sent events.
+pub fn is_event_stream(headers: &Headers) -> bool {
+    headers.get("content-type").is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("text/event-stream")
+    })
+}
+
+/// Parses the events of a response body while it is being received, the lines are parsed
+/// only once.
+#[derive(Debug, Default)]
+pub struct Parser {
+    /// The position in the body up to which the lines are parsed.
+    pos: usize,
+    last_id: Option<String>,
+    event_type: Option<String>,
+    data: String,
+}
+
+impl Parser {
+    /// Parse the lines added to the body since the previous call, `body` is the body
+    /// received so far. An event is complete when an empty line follows it, the events
+    /// completed by the new lines are given back. The timestamps of the events are left
+    /// for the caller.
+    pub fn parse(&mut self, body: &[u8]) -> Vec<SseEvent> {
+        let mut events = vec![];
+
+        if self.pos == 0 && body.starts_with(b"\xef\xbb\xbf") {
+            self.pos = 3;
+        }
+
+        // Lines end with CRLF, LF or CR
+        while let Some(end) = body
+            .get(self.pos..)
+            .and_then(|rest| rest.iter().position(|b| *b == b'\r' || *b == b'\n'))
+        {
+            let start = self.pos;
+            let end = start + end;
+
+            self.pos = match &body[end..] {
+                // The LF of the CRLF may be in the next packet
+                b"\r" => break,
+                eol if eol.starts_with(b"\r\n") => end + 2,
+                _ => end + 1,
+            };
+
+            let line = String::from_utf8_lossy(&body[start..end]);
+
+            events.extend(self.parse_line(&line));
+        }
+
+        events
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            let event_type = self.event_type.take();
+
+            // The event is dispatched only if it has data
+            if self.data.is_empty() {
+                return None;
+            }
+
+            self.data.pop();
+
+            return Some(SseEvent {
+                id: self.last_id.clone(),
+                event: event_type,
+                data: std::mem::take(&mut self.data),
+                ..Default::default()
+            });
+        }
+
+        if line.starts_with(':') {
+            // Comment, servers send them to keep the connection alive
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event_type = Some(value.to_string()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_id = Some(value.to_string()),
+            _ => {}
+        }
+
+        None
+    }
+}
@@ -0,0 +1,941 @@
+// Decrypting TLS 1.2 and 1.3 connections with the secrets of an NSS key log file
+// (SSLKEYLOGFILE)
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm,
+};
+use bytes::BytesMut;
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use sha2::{Sha256, Sha384};
+
+use super::{reassembly::ByteStream, ParseError};
+
+const RECORD_HEADER_LEN: usize = 5;
+const TAG_LEN: usize = 16;
+
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+const HEARTBEAT: u8 = 24;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const KEY_UPDATE: u8 = 24;
+
+const TLS12: u16 = 0x0303;
+const TLS13: u16 = 0x0304;
+
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// The secrets of the TLS sessions, read from a key log file. The file is read again when
+/// a secret is not found, since during a live capture the clients keep appending it.
+pub struct KeyLog {
+    path: PathBuf,
+    /// The secrets by their label and the client random of the session.
+    secrets: HashMap<(String, Vec<u8>), Vec<u8>>,
+    /// The length of the file which has been read so far.
+    read_len: u64,
+}
+
+impl KeyLog {
+    pub fn new(path: PathBuf) -> Self {
+        let mut keylog = KeyLog {
+            path,
+            secrets: HashMap::new(),
+            read_len: 0,
+        };
+
+        keylog.reload();
+
+        info!(
+            "{} TLS secrets loaded from {}",
+            keylog.secrets.len(),
+            keylog.path.display()
+        );
+
+        keylog
+    }
+
+    fn secret(&mut self, label: &str, client_random: &[u8]) -> Option<Vec<u8>> {
+        let key = (label.to_string(), client_random.to_vec());
+
+        if !self.secrets.contains_key(&key) {
+            self.reload();
+        }
+
+        self.secrets.get(&key).cloned()
+    }
+
+    /// Read the lines appended to the file since the last read.
+    fn reload(&mut self) {
+        let mut content = vec![];
+        let result = File::open(&self.path).and_then(|mut file| {
+            if file.metadata()?.len() < self.read_len {
+                // The file is truncated, read it again
+                self.read_len = 0;
+            }
+
+            file.seek(SeekFrom::Start(self.read_len))?;
+            file.read_to_end(&mut content)
+        });
+
+        if let Err(e) = result {
+            error!("Cannot read key log file {}: {e}", self.path.display());
+
+            return;
+        }
+
+        // The last line may be written right now
+        let Some(end) = content.iter().rposition(|b| *b == b'\n') else {
+            return;
+        };
+
+        self.read_len += end as u64 + 1;
+
+        for line in String::from_utf8_lossy(&content[..end]).lines() {
+            let mut fields = line.split_whitespace();
+
+            let (Some(label), Some(client_random), Some(secret)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+
+            if label.starts_with('#') {
+                continue;
+            }
+
+            if let (Some(client_random), Some(secret)) = (from_hex(client_random), from_hex(secret))
+            {
+                self.secrets
+                    .insert((label.to_string(), client_random), secret);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for KeyLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyLog")
+            .field("path", &self.path)
+            .field("secrets", &self.secrets.len())
+            .finish()
+    }
+}
+
+/// Checks if the payload starts with a TLS ClientHello or ServerHello record. Gives back
+/// if it is sent by the client.
+pub fn hello_sender(payload: &[u8]) -> Option<bool> {
+    if payload.len() <= RECORD_HEADER_LEN || payload[0] != HANDSHAKE || payload[1] != 3 {
+        return None;
+    }
+
+    match payload[RECORD_HEADER_LEN] {
+        CLIENT_HELLO => Some(true),
+        SERVER_HELLO => Some(false),
+        _ => None,
+    }
+}
+
+/// Checks if the bytes can be the beginning of a ClientHello record.
+pub fn is_client_hello_start(bytes: &[u8]) -> bool {
+    if bytes.len() > RECORD_HEADER_LEN {
+        return hello_sender(bytes) == Some(true);
+    }
+
+    bytes.first() == Some(&HANDSHAKE) && bytes.get(1).is_none_or(|major| *major == 3)
+}
+
+#[derive(Clone, Copy)]
+enum Hash {
+    Sha256,
+    Sha384,
+}
+
+impl Hash {
+    fn len(self) -> usize {
+        match self {
+            Hash::Sha256 => 32,
+            Hash::Sha384 => 48,
+        }
+    }
+
+    /// The pseudorandom function of TLS 1.2 (RFC 5246 section 5).
+    fn prf(self, secret: &[u8], label: &str, seed: &[u8], len: usize) -> Option<Vec<u8>> {
+        let seed = [label.as_bytes(), seed].concat();
+
+        match self {
+            Hash::Sha256 => p_hash::<Hmac<Sha256>>(secret, &seed, len),
+            Hash::Sha384 => p_hash::<Hmac<Sha384>>(secret, &seed, len),
+        }
+    }
+
+    /// HKDF-Expand-Label of TLS 1.3 (RFC 8446 section 7.1) with empty context.
+    fn expand_label(self, secret: &[u8], label: &str, len: usize) -> Option<Vec<u8>> {
+        let label = format!("tls13 {label}");
+        let mut info = vec![];
+
+        info.extend_from_slice(&(len as u16).to_be_bytes());
+        info.push(label.len() as u8);
+        info.extend_from_slice(label.as_bytes());
+        info.push(0);
+
+        let mut okm = vec![0; len];
+
+        match self {
+            Hash::Sha256 => Hkdf::<Sha256>::from_prk(secret)
+                .ok()?
+                .expand(&info, &mut okm)
+                .ok()?,
+            Hash::Sha384 => Hkdf::<Sha384>::from_prk(secret)
+                .ok()?
+                .expand(&info, &mut okm)
+                .ok()?,
+        }
+
+        Some(okm)
+    }
+}
+
+fn p_hash<M: Mac + KeyInit>(secret: &[u8], seed: &[u8], len: usize) -> Option<Vec<u8>> {
+    let hmac = |parts: &[&[u8]]| -> Option<Vec<u8>> {
+        let mut mac = <M as KeyInit>::new_from_slice(secret).ok()?;
+
+        for part in parts {
+            mac.update(part);
+        }
+
+        Some(mac.finalize().into_bytes().to_vec())
+    };
+
+    let mut a = hmac(&[seed])?;
+    let mut output = vec![];
+
+    while output.len() < len {
+        output.extend(hmac(&[&a, seed])?);
+        a = hmac(&[&a])?;
+    }
+
+    output.truncate(len);
+
+    Some(output)
+}
+
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Algorithm {
+    fn key_len(self) -> usize {
+        match self {
+            Algorithm::Aes128Gcm => 16,
+            Algorithm::Aes256Gcm | Algorithm::ChaCha20Poly1305 => 32,
+        }
+    }
+}
+
+/// The AEAD cipher suites which can be decrypted.
+fn cipher_suite(id: u16) -> Option<(Algorithm, Hash)> {
+    let suite = match id {
+        0x1301 | 0x009c | 0x009e | 0xc02b | 0xc02f => (Algorithm::Aes128Gcm, Hash::Sha256),
+        0x1302 | 0x009d | 0x009f | 0xc02c | 0xc030 => (Algorithm::Aes256Gcm, Hash::Sha384),
+        0x1303 | 0xcca8 | 0xcca9 | 0xccaa => (Algorithm::ChaCha20Poly1305, Hash::Sha256),
+        _ => return None,
+    };
+
+    Some(suite)
+}
+
+enum Cipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(algorithm: Algorithm, key: &[u8]) -> Option<Self> {
+        let cipher = match algorithm {
+            Algorithm::Aes128Gcm => {
+                Cipher::Aes128Gcm(Box::new(Aes128Gcm::new_from_slice(key).ok()?))
+            }
+            Algorithm::Aes256Gcm => {
+                Cipher::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(key).ok()?))
+            }
+            Algorithm::ChaCha20Poly1305 => {
+                Cipher::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new_from_slice(key).ok()?))
+            }
+        };
+
+        Some(cipher)
+    }
+
+    fn open(&self, nonce: &[u8], aad: &[u8], msg: &[u8]) -> Option<Vec<u8>> {
+        let nonce = GenericArray::from_slice(nonce);
+        let payload = Payload { msg, aad };
+
+        match self {
+            Cipher::Aes128Gcm(cipher) => cipher.decrypt(nonce, payload).ok(),
+            Cipher::Aes256Gcm(cipher) => cipher.decrypt(nonce, payload).ok(),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce, payload).ok(),
+        }
+    }
+}
+
+/// The record protection of one direction.
+struct Decrypter {
+    cipher: Cipher,
+    iv: Vec<u8>,
+    seq: u64,
+    version: u16,
+}
+
+impl Decrypter {
+    fn new(algorithm: Algorithm, key: &[u8], iv: Vec<u8>, version: u16) -> Option<Self> {
+        Some(Decrypter {
+            cipher: Cipher::new(algorithm, key)?,
+            iv,
+            seq: 0,
+            version,
+        })
+    }
+
+    /// Decrypt the record. It gives back `None` if the record is not protected with this
+    /// key.
+    fn open(&mut self, record: &[u8]) -> Option<Vec<u8>> {
+        let (header, payload) = record.split_at(RECORD_HEADER_LEN);
+        let seq = self.seq.to_be_bytes();
+
+        let plaintext = if self.iv.len() == 4 {
+            // AES-GCM of TLS 1.2 sends the second part of the nonce in the record
+            if payload.len() < 8 + TAG_LEN {
+                return None;
+            }
+
+            let (explicit, ciphertext) = payload.split_at(8);
+            let nonce = [&self.iv[..], explicit].concat();
+            let aad = tls12_aad(&seq, header, ciphertext.len() - TAG_LEN);
+
+            self.cipher.open(&nonce, &aad, ciphertext)?
+        } else {
+            if payload.len() < TAG_LEN {
+                return None;
+            }
+
+            let mut nonce = self.iv.clone();
+
+            for (i, b) in seq.iter().enumerate() {
+                nonce[4 + i] ^= b;
+            }
+
+            let aad = if self.version == TLS13 {
+                header.to_vec()
+            } else {
+                tls12_aad(&seq, header, payload.len() - TAG_LEN)
+            };
+
+            self.cipher.open(&nonce, &aad, payload)?
+        };
+
+        self.seq += 1;
+
+        Some(plaintext)
+    }
+}
+
+fn tls12_aad(seq: &[u8], header: &[u8], len: usize) -> Vec<u8> {
+    [seq, &header[..3], &(len as u16).to_be_bytes()].concat()
+}
+
+/// One direction of the connection.
+#[derive(Default)]
+struct Direction {
+    /// The bytes of the records.
+    raw: ByteStream,
+    /// TLS 1.2: ChangeCipherSpec is received, the following records are encrypted.
+    cipher_changed: bool,
+    decrypter: Option<Decrypter>,
+    /// TLS 1.3: the records are still protected with the handshake traffic secret.
+    handshake: bool,
+    /// TLS 1.3: the traffic secret of the decrypter, the next one is derived from it when
+    /// the key is updated.
+    secret: Vec<u8>,
+}
+
+/// A TLS session on a tcp connection. The records of both directions are decrypted, and
+/// the application data goes on to the HTTP parser.
+pub struct Session {
+    client: Direction,
+    server: Direction,
+    client_random: Option<Vec<u8>>,
+    server_random: Option<Vec<u8>>,
+    /// The version and the cipher suite chosen by the server.
+    version: Option<u16>,
+    cipher_suite: Option<u16>,
+    /// The connection cannot be decrypted further.
+    error: Option<String>,
+    error_reported: bool,
+}
+
+impl Session {
+    /// Start decrypting the connection, the bytes received so far are the records.
+    pub fn new(client: ByteStream, server: ByteStream) -> Self {
+        Session {
+            client: Direction {
+                raw: client,
+                ..Default::default()
+            },
+            server: Direction {
+                raw: server,
+                ..Default::default()
+            },
+            client_random: None,
+            server_random: None,
+            version: None,
+            cipher_suite: None,
+            error: None,
+            error_reported: false,
+        }
+    }
+
+    /// The byte streams of the records sent by the client and by the server.
+    pub fn raw_streams(&mut self) -> (&mut ByteStream, &mut ByteStream) {
+        (&mut self.client.raw, &mut self.server.raw)
+    }
+
+    /// Decrypt the records received so far, the application data is appended to the
+    /// request and response byte streams. Encrypted records wait till their secrets
+    /// appear in the key log.
+    pub fn decrypt(
+        &mut self,
+        keylog: &mut KeyLog,
+        request: &mut ByteStream,
+        response: &mut ByteStream,
+    ) {
+        // The records of one side may wait for the handshake messages of the other side
+        loop {
+            let mut progress = false;
+
+            for (from_client, plain) in [(true, &mut *request), (false, &mut *response)] {
+                match self.receive(from_client, keylog, plain) {
+                    Ok(received) => progress |= received,
+                    Err(e) => {
+                        self.error.get_or_insert(e);
+                    }
+                }
+            }
+
+            if !progress || self.error.is_some() {
+                break;
+            }
+        }
+
+        if self.error.is_some() {
+            self.client.raw.discard();
+            self.server.raw.discard();
+        }
+    }
+
+    /// The connection is over, the records which could not be decrypted are lost.
+    pub fn close(&mut self) {
+        self.client.raw.close();
+        self.server.raw.close();
+
+        if self.client.raw.peek().is_empty() && self.server.raw.peek().is_empty() {
+            return;
+        }
+
+        let error = if self.cipher_suite.is_none() {
+            "TLS handshake is not complete".to_string()
+        } else if self.client_random.is_none() {
+            "ClientHello is missing from the capture".to_string()
+        } else if self.client.decrypter.is_none() || self.server.decrypter.is_none() {
+            "The secrets of the TLS session are not in the key log".to_string()
+        } else {
+            "Incomplete TLS record".to_string()
+        };
+
+        self.error.get_or_insert(error);
+    }
+
+    /// The reason why decryption stopped, it is given back only once.
+    pub fn take_error(&mut self) -> Option<String> {
+        if self.error_reported {
+            return None;
+        }
+
+        self.error_reported = self.error.is_some();
+        self.error.clone()
+    }
+
+    /// Handle the complete records of one direction. Gives back if any record was
+    /// handled.
+    fn receive(
+        &mut self,
+        from_client: bool,
+        keylog: &mut KeyLog,
+        plain: &mut ByteStream,
+    ) -> Result<bool, String> {
+        let mut progress = false;
+
+        while self.error.is_none() {
+            let direction = self.direction(from_client);
+            let bytes = direction.raw.peek();
+
+            if bytes.len() >= RECORD_HEADER_LEN
+                && (!(CHANGE_CIPHER_SPEC..=HEARTBEAT).contains(&bytes[0]) || bytes[1] != 3)
+            {
+                return Err("Invalid TLS record".to_string());
+            }
+
+            let len = match bytes.get(3..RECORD_HEADER_LEN) {
+                Some(len) => RECORD_HEADER_LEN + u16::from_be_bytes([len[0], len[1]]) as usize,
+                None => usize::MAX,
+            };
+
+            if bytes.len() < len {
+                if direction.raw.has_gap() {
+                    return Err("Bytes are missing from the capture".to_string());
+                }
+
+                break;
+            }
+
+            let record = bytes[..len].to_vec();
+
+            if !self.handle_record(from_client, &record, keylog, plain)? {
+                // Waiting for the other side or for the key log
+                break;
+            }
+
+            let _ = self.direction(from_client).raw.parse_message(
+                |b: &mut BytesMut, _| -> Result<(), ParseError> {
+                    let _ = b.split_to(len);
+
+                    Ok(())
+                },
+                |_| false,
+            );
+
+            progress = true;
+        }
+
+        Ok(progress)
+    }
+
+    /// Handle a record, gives back false if it cannot be handled yet.
+    fn handle_record(
+        &mut self,
+        from_client: bool,
+        record: &[u8],
+        keylog: &mut KeyLog,
+        plain: &mut ByteStream,
+    ) -> Result<bool, String> {
+        let content_type = record[0];
+        let payload = &record[RECORD_HEADER_LEN..];
+        let hello = content_type == HANDSHAKE
+            && payload.first()
+                == Some(if from_client {
+                    &CLIENT_HELLO
+                } else {
+                    &SERVER_HELLO
+                });
+
+        if self.version.is_none() && !hello {
+            // Everything else depends on the version chosen by the server
+            return Ok(false);
+        }
+
+        let encrypted = match self.version {
+            Some(TLS13) => content_type == APPLICATION_DATA,
+            _ => self.direction(from_client).cipher_changed,
+        };
+
+        if !encrypted {
+            if hello && from_client {
+                self.client_random = payload.get(6..38).map(<[u8]>::to_vec);
+            } else if hello {
+                self.server_hello(payload)?;
+            } else if content_type == CHANGE_CIPHER_SPEC && self.version == Some(TLS12) {
+                self.direction(from_client).cipher_changed = true;
+            }
+
+            return Ok(true);
+        }
+
+        if self.direction(from_client).decrypter.is_none() {
+            let Some(decrypter) = self.decrypter(from_client, false, keylog)? else {
+                return Ok(false);
+            };
+
+            let handshake = self.version == Some(TLS13);
+            let direction = self.direction(from_client);
+
+            direction.secret = decrypter.1;
+            direction.decrypter = Some(decrypter.0);
+            direction.handshake = handshake;
+        }
+
+        let mut plaintext = self
+            .direction(from_client)
+            .decrypter
+            .as_mut()
+            .and_then(|decrypter| decrypter.open(record));
+
+        if plaintext.is_none() && self.direction(from_client).handshake {
+            // The handshake is over, the application traffic secret is used
+            let Some((decrypter, secret)) = self.decrypter(from_client, true, keylog)? else {
+                return Ok(false);
+            };
+
+            let direction = self.direction(from_client);
+
+            direction.secret = secret;
+            direction.decrypter = Some(decrypter);
+            direction.handshake = false;
+
+            plaintext = direction
+                .decrypter
+                .as_mut()
+                .and_then(|decrypter| decrypter.open(record));
+        }
+
+        let Some(mut plaintext) = plaintext else {
+            return Err("Cannot decrypt TLS record, the secret does not match".to_string());
+        };
+
+        let content_type = if self.version == Some(TLS13) {
+            // The real content type follows the content, then the padding
+            let Some(end) = plaintext.iter().rposition(|b| *b != 0) else {
+                return Err("Invalid TLS 1.3 record".to_string());
+            };
+
+            let content_type = plaintext[end];
+
+            plaintext.truncate(end);
+
+            content_type
+        } else {
+            content_type
+        };
+
+        match content_type {
+            APPLICATION_DATA => plain.push(&plaintext),
+            HANDSHAKE
+                if self.version == Some(TLS13)
+                    && !self.direction(from_client).handshake
+                    && plaintext.first() == Some(&KEY_UPDATE) =>
+            {
+                self.update_key(from_client)?;
+            }
+            // Finished, alerts and the rest are not shown
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    fn direction(&mut self, from_client: bool) -> &mut Direction {
+        if from_client {
+            &mut self.client
+        } else {
+            &mut self.server
+        }
+    }
+
+    /// Find the random, the version and the cipher suite in the ServerHello.
+    fn server_hello(&mut self, message: &[u8]) -> Result<(), String> {
+        let invalid = || "Invalid ServerHello".to_string();
+
+        let random = message.get(6..38).ok_or_else(invalid)?;
+        let session_id_len = *message.get(38).ok_or_else(invalid)? as usize;
+        let mut pos = 39 + session_id_len;
+        let suite = message.get(pos..pos + 2).ok_or_else(invalid)?;
+
+        self.server_random = Some(random.to_vec());
+        self.cipher_suite = Some(u16::from_be_bytes([suite[0], suite[1]]));
+        self.version = Some(TLS12);
+
+        // The compression method and the length of the extensions
+        pos += 5;
+
+        while let Some(extension) = message.get(pos..pos + 4) {
+            let kind = u16::from_be_bytes([extension[0], extension[1]]);
+            let len = u16::from_be_bytes([extension[2], extension[3]]) as usize;
+
+            if kind == SUPPORTED_VERSIONS {
+                let version = message.get(pos + 4..pos + 6).ok_or_else(invalid)?;
+
+                self.version = Some(u16::from_be_bytes([version[0], version[1]]));
+            }
+
+            pos += 4 + len;
+        }
+
+        Ok(())
+    }
+
+    /// Make the decrypter of the direction from the secrets in the key log. In TLS 1.3
+    /// the handshake or the application traffic secret is used. Gives back `None` if the
+    /// secret is not in the key log yet.
+    fn decrypter(
+        &self,
+        from_client: bool,
+        application: bool,
+        keylog: &mut KeyLog,
+    ) -> Result<Option<(Decrypter, Vec<u8>)>, String> {
+        let suite = self.cipher_suite.unwrap_or_default();
+        let (algorithm, hash) =
+            cipher_suite(suite).ok_or(format!("Unsupported cipher suite 0x{suite:04x}"))?;
+        let Some(ref client_random) = self.client_random else {
+            // The ClientHello may be completed by a later segment
+            return Ok(None);
+        };
+
+        let version = self.version.unwrap_or(TLS12);
+        let invalid = || "Invalid secret in the key log".to_string();
+
+        if version == TLS13 {
+            let label = match (from_client, application) {
+                (true, false) => "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+                (false, false) => "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+                (true, true) => "CLIENT_TRAFFIC_SECRET_0",
+                (false, true) => "SERVER_TRAFFIC_SECRET_0",
+            };
+
+            let Some(secret) = keylog.secret(label, client_random) else {
+                return Ok(None);
+            };
+
+            let decrypter = tls13_decrypter(algorithm, hash, &secret).ok_or_else(invalid)?;
+
+            return Ok(Some((decrypter, secret)));
+        }
+
+        let Some(ref server_random) = self.server_random else {
+            return Err("ServerHello is missing from the capture".to_string());
+        };
+
+        let Some(master_secret) = keylog.secret("CLIENT_RANDOM", client_random) else {
+            return Ok(None);
+        };
+
+        let key_len = algorithm.key_len();
+        let iv_len = match algorithm {
+            Algorithm::ChaCha20Poly1305 => 12,
+            _ => 4,
+        };
+
+        // The key block is the client key, the server key, the client IV and the server IV
+        let seed = [&server_random[..], &client_random[..]].concat();
+        let key_block = hash
+            .prf(
+                &master_secret,
+                "key expansion",
+                &seed,
+                2 * key_len + 2 * iv_len,
+            )
+            .ok_or_else(invalid)?;
+
+        let (key, iv) = if from_client {
+            (0, 2 * key_len)
+        } else {
+            (key_len, 2 * key_len + iv_len)
+        };
+
+        let decrypter = Decrypter::new(
+            algorithm,
+            &key_block[key..key + key_len],
+            key_block[iv..iv + iv_len].to_vec(),
+            version,
+        )
+        .ok_or_else(invalid)?;
+
+        Ok(Some((decrypter, master_secret)))
+    }
+
+    /// KeyUpdate of TLS 1.3: the next records are protected with a new secret.
+    fn update_key(&mut self, from_client: bool) -> Result<(), String> {
+        let suite = self.cipher_suite.unwrap_or_default();
+        let (algorithm, hash) =
+            cipher_suite(suite).ok_or(format!("Unsupported cipher suite 0x{suite:04x}"))?;
+        let direction = self.direction(from_client);
+        let invalid = || "Cannot update the TLS key".to_string();
+
+        let secret = hash
+            .expand_label(&direction.secret, "traffic upd", hash.len())
+            .ok_or_else(invalid)?;
+
+        direction.decrypter = Some(tls13_decrypter(algorithm, hash, &secret).ok_or_else(invalid)?);
+        direction.secret = secret;
+
+        Ok(())
+    }
+}
+
+fn tls13_decrypter(algorithm: Algorithm, hash: Hash, secret: &[u8]) -> Option<Decrypter> {
+    let key = hash.expand_label(secret, "key", algorithm.key_len())?;
+    let iv = hash.expand_label(secret, "iv", 12)?;
+
+    Decrypter::new(algorithm, &key, iv, TLS13)
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        from_hex(&s.replace(' ', "")).unwrap()
+    }
+
+    // The vectors of the TLS 1.2 PRF published on the IETF TLS mailing list
+    #[test]
+    fn tls12_prf_sha256() {
+        let output = Hash::Sha256
+            .prf(
+                &hex("9bbe436ba940f017b17652849a71db35"),
+                "test label",
+                &hex("a0ba9f936cda311827a6f796ffd5198c"),
+                100,
+            )
+            .unwrap();
+
+        let expected = hex(
+            "e3f229ba727be17b8d122620557cd453c2aab21d07c3d495329b52d4e61edb5a\
+             6b301791e90d35c9c9a46b4e14baf9af0fa022f7077def17abfd3797c0564bab\
+             4fbc91666e9def9b97fce34f796789baa48082d122ee42c5a72e5a5110fff701\
+             87347b66",
+        );
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn tls12_prf_sha384() {
+        let output = Hash::Sha384
+            .prf(
+                &hex("b80b733d6ceefcdc71566ea48e5567df"),
+                "test label",
+                &hex("cd665cf6a8447dd6ff8b27555edb7465"),
+                148,
+            )
+            .unwrap();
+
+        let expected = hex(
+            "7b0c18e9ced410ed1804f2cfa34a336a1c14dffb4900bb5fd7942107e81c83cd\
+             e9ca0faa60be9fe34f82b1233c9146a0e534cb400fed2700884f9dc236f80edd\
+             8bfa961144c9e8d792eca722a7b32fc3d416d473ebc2c5fd4abfdad05d918425\
+             9b5bf8cd4d90fa0d31e2dec479e4f1a26066f2eea9a69236a3e52655c9e9aee6\
+             91c8f3a26854308d5eaa3be85e0990703d73e56f",
+        );
+
+        assert_eq!(output, expected);
+    }
+
+    // The traffic secrets and keys of the simple 1-RTT handshake of RFC 8448 section 3
+    #[test]
+    fn tls13_traffic_keys() {
+        let vectors = [
+            // Server handshake traffic
+            (
+                "b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38",
+                "3fce516009c21727d0f2e4e86ee403bc",
+                "5d313eb2671276ee13000b30",
+            ),
+            // Client handshake traffic
+            (
+                "b3eddb126e067f35a780b3abf45e2d8f3b1a950738f52e9600746a0e27a55a21",
+                "dbfaa693d1762c5b666af5d950258d01",
+                "5bd3c71b836e0b76bb73265f",
+            ),
+            // Server application traffic
+            (
+                "a11af9f05531f856ad47116b45a950328204b4f44bfb6b3a4b4f1f3fcb631643",
+                "9f02283b6c9c07efc26bb9f2ac92e356",
+                "cf782b88dd83549aadf1e984",
+            ),
+        ];
+
+        for (secret, key, iv) in vectors {
+            let secret = hex(secret);
+
+            assert_eq!(
+                Hash::Sha256.expand_label(&secret, "key", 16).unwrap(),
+                hex(key)
+            );
+            assert_eq!(
+                Hash::Sha256.expand_label(&secret, "iv", 12).unwrap(),
+                hex(iv)
+            );
+        }
+    }
+
+    /// Protect a TLS 1.3 record as RFC 8446 section 5.2 tells.
+    fn seal(key: &[u8], iv: &[u8], seq: u64, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = iv.to_vec();
+
+        for (i, b) in seq.to_be_bytes().iter().enumerate() {
+            nonce[4 + i] ^= b;
+        }
+
+        let len = (plaintext.len() + 1 + TAG_LEN) as u16;
+        let mut record = vec![APPLICATION_DATA, 3, 3];
+
+        record.extend_from_slice(&len.to_be_bytes());
+
+        let payload = Payload {
+            msg: &[plaintext, &[APPLICATION_DATA]].concat(),
+            aad: &record,
+        };
+        let ciphertext = Aes128Gcm::new_from_slice(key)
+            .unwrap()
+            .encrypt(GenericArray::from_slice(&nonce), payload)
+            .unwrap();
+
+        record.extend(ciphertext);
+        record
+    }
+
+    #[test]
+    fn tls13_records() {
+        let secret = hex("a11af9f05531f856ad47116b45a950328204b4f44bfb6b3a4b4f1f3fcb631643");
+        let key = hex("9f02283b6c9c07efc26bb9f2ac92e356");
+        let iv = hex("cf782b88dd83549aadf1e984");
+        let mut decrypter = tls13_decrypter(Algorithm::Aes128Gcm, Hash::Sha256, &secret).unwrap();
+
+        let first = seal(&key, &iv, 0, b"GET / HTTP/1.1\r\n");
+        let second = seal(&key, &iv, 1, b"Host: example.com\r\n");
+
+        assert_eq!(decrypter.open(&first).unwrap(), b"GET / HTTP/1.1\r\n\x17");
+        // The sequence number is part of the nonce, a replayed record does not decrypt
+        assert!(decrypter.open(&first).is_none());
+        assert_eq!(
+            decrypter.open(&second).unwrap(),
+            b"Host: example.com\r\n\x17"
+        );
+    }
+}
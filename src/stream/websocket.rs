@@ -0,0 +1,509 @@
+// Decoding WebSocket frames after the HTTP/1.1 upgrade (RFC 6455, RFC 7692)
+
+use std::time::Duration;
+
+use bytes::BytesMut;
+use flate2::{Decompress, FlushDecompress, Status};
+
+use super::{encoding::MAX_DECODED_BYTES, reassembly::ByteStream, ParseError, PendingExchange};
+use crate::ui::stream::{Opcode, Resp, WsMessage};
+
+const FIN: u8 = 0x80;
+/// RSV1 marks the first frame of a compressed message.
+const COMPRESSED: u8 = 0x40;
+const MASKED: u8 = 0x80;
+
+/// A compressed message is sent without the end of the last deflate block.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+struct Frame {
+    fin: bool,
+    compressed: bool,
+    opcode: u8,
+    payload: BytesMut,
+}
+
+/// The state of one direction of the connection.
+#[derive(Default)]
+struct Sender {
+    /// The frames of a fragmented message received so far.
+    fragmented: Option<WsMessage>,
+    /// The compression context of permessage-deflate.
+    inflater: Option<Decompress>,
+    /// The sender compresses every message with a new context.
+    no_context_takeover: bool,
+    /// The close frame has been sent.
+    closed: bool,
+}
+
+impl Sender {
+    /// Decompress a message of permessage-deflate.
+    fn inflate(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let inflater = self.inflater.get_or_insert_with(|| Decompress::new(false));
+
+        if self.no_context_takeover {
+            inflater.reset(false);
+        }
+
+        let mut input = data.to_vec();
+
+        input.extend_from_slice(&DEFLATE_TAIL);
+
+        let mut output = Vec::with_capacity(input.len() * 4);
+        let mut pos = 0;
+
+        loop {
+            if output.len() > MAX_DECODED_BYTES {
+                return Err("Cannot inflate message: decoded message is too large".to_string());
+            }
+
+            if output.len() == output.capacity() {
+                let limit = MAX_DECODED_BYTES + 1 - output.len();
+
+                output.reserve(output.capacity().max(4096).min(limit));
+            }
+
+            let (total_in, total_out) = (inflater.total_in(), inflater.total_out());
+            let status = inflater
+                .decompress_vec(&input[pos..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| format!("Cannot inflate message: {e}"))?;
+
+            pos += (inflater.total_in() - total_in) as usize;
+
+            let progress = inflater.total_in() != total_in || inflater.total_out() != total_out;
+
+            if status == Status::StreamEnd
+                || !progress
+                || (pos == input.len() && output.len() < output.capacity())
+            {
+                return Ok(output);
+            }
+        }
+    }
+}
+
+/// A WebSocket connection: the handshake exchange and the messages sent after it. The
+/// handshake is emitted in progress while the connection is open, and with all the
+/// messages when the connection is closed.
+pub struct Connection {
+    handshake: Option<(PendingExchange, Resp)>,
+    client: Sender,
+    server: Sender,
+    /// The permessage-deflate extension is negotiated in the handshake.
+    deflate: bool,
+    messages: Vec<WsMessage>,
+    /// The number of messages and the time of the last update emitted in progress.
+    live: Option<(usize, Duration)>,
+}
+
+impl Connection {
+    pub fn new(pending: PendingExchange, response: Resp) -> Self {
+        let mut connection = Connection {
+            handshake: None,
+            client: Sender::default(),
+            server: Sender::default(),
+            deflate: false,
+            messages: vec![],
+            live: None,
+        };
+
+        let extensions = response.headers.get_all("sec-websocket-extensions");
+
+        for extension in extensions.flat_map(|value| value.split(',')) {
+            let mut params = extension.split(';').map(str::trim);
+
+            if params.next() != Some("permessage-deflate") {
+                continue;
+            }
+
+            connection.deflate = true;
+
+            for param in params {
+                match param.split('=').next().unwrap_or_default().trim() {
+                    "client_no_context_takeover" => connection.client.no_context_takeover = true,
+                    "server_no_context_takeover" => connection.server.no_context_takeover = true,
+                    _ => {}
+                }
+            }
+        }
+
+        connection.handshake = Some((pending, response));
+
+        connection
+    }
+
+    /// Decode the frames received from the client or from the server. `now` is the time
+    /// of the last packet, the messages completed by it are stamped with that.
+    pub fn receive(&mut self, from_client: bool, bytes: &mut ByteStream, now: Duration) {
+        if self.handshake.is_none() {
+            bytes.discard();
+
+            return;
+        }
+
+        loop {
+            match bytes.parse_message(parse_frame, |_| false) {
+                Ok((_, missing)) if missing > 0 => {
+                    // The frame boundaries are lost after a gap
+                    self.set_broken(format!("{missing} bytes are missing from the capture"));
+                    bytes.discard();
+
+                    return;
+                }
+                Ok((frame, _)) => self.handle_frame(from_client, frame, now),
+                Err(ParseError::Incomplete(_)) if !bytes.is_closed() => return,
+                Err(e) => {
+                    if !bytes.peek().is_empty() {
+                        self.set_broken(e.to_string());
+                    }
+
+                    bytes.discard();
+
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Take the handshake exchange with the messages, when both sides have sent the close
+    /// frame, the decoding stopped or the connection is closed.
+    pub fn take_finished(&mut self, closed: bool) -> Option<(PendingExchange, Resp)> {
+        let finished = self.client.closed && self.server.closed;
+        let broken = self
+            .handshake
+            .as_ref()
+            .is_some_and(|(pending, _)| pending.error.is_some());
+
+        if !(finished || broken || closed) {
+            return None;
+        }
+
+        let (mut pending, response) = self.handshake.take()?;
+
+        for sender in [&mut self.client, &mut self.server] {
+            if let Some(mut message) = sender.fragmented.take() {
+                message.error = Some("Message is not finished".to_string());
+
+                self.messages.push(message);
+            }
+        }
+
+        pending.messages = std::mem::take(&mut self.messages);
+
+        Some((pending, response))
+    }
+
+    /// A copy of the handshake exchange with the messages decoded since the previous update,
+    /// to show it while the connection is open. It is given back first right after the
+    /// upgrade, then when new messages arrive, at most once in `interval`.
+    pub fn live_update(
+        &mut self,
+        now: Duration,
+        interval: Duration,
+    ) -> Option<(PendingExchange, Resp)> {
+        let (pending, response) = self.handshake.as_ref()?;
+        let mut sent = 0;
+
+        if let Some((messages, time)) = self.live {
+            if self.messages.len() <= messages || now.saturating_sub(time) < interval {
+                return None;
+            }
+
+            sent = messages;
+        }
+
+        self.live = Some((self.messages.len(), now));
+
+        let mut snapshot = pending.snapshot();
+
+        snapshot.messages = self.messages[sent..].to_vec();
+
+        Some((snapshot, response.clone()))
+    }
+
+    /// Give up decoding, the messages decoded so far are emitted with the error.
+    fn set_broken(&mut self, error: String) {
+        if let Some((ref mut pending, _)) = self.handshake {
+            pending
+                .error
+                .get_or_insert(format!("WebSocket decoding stopped: {error}"));
+        }
+    }
+
+    fn handle_frame(&mut self, from_client: bool, frame: Frame, now: Duration) {
+        let sender = if from_client {
+            &mut self.client
+        } else {
+            &mut self.server
+        };
+
+        let mut message = match frame.opcode {
+            // Continuation of a fragmented message
+            0x0 => match sender.fragmented.take() {
+                Some(mut message) => {
+                    message.data.extend_from_slice(&frame.payload);
+
+                    message
+                }
+                None => WsMessage {
+                    from_client,
+                    timestamp: now,
+                    opcode: Opcode::Continuation,
+                    data: frame.payload.to_vec(),
+                    compressed: false,
+                    error: Some("Continuation without a message".to_string()),
+                },
+            },
+            opcode => {
+                let opcode = Opcode::from(opcode);
+
+                if opcode == Opcode::Close {
+                    sender.closed = true;
+                }
+
+                if !opcode.is_control() {
+                    if let Some(mut unfinished) = sender.fragmented.take() {
+                        // A new message before the previous one is finished
+                        unfinished.error = Some("Message is not finished".to_string());
+
+                        self.messages.push(unfinished);
+                    }
+                }
+
+                WsMessage {
+                    from_client,
+                    timestamp: now,
+                    opcode,
+                    data: frame.payload.to_vec(),
+                    compressed: frame.compressed && self.deflate,
+                    error: None,
+                }
+            }
+        };
+
+        if !frame.fin && !message.opcode.is_control() {
+            sender.fragmented = Some(message);
+
+            return;
+        }
+
+        if message.compressed {
+            match sender.inflate(&message.data) {
+                Ok(data) => message.data = data,
+                Err(e) => message.error = Some(e),
+            }
+        }
+
+        self.messages.push(message);
+    }
+}
+
+fn parse_frame(bytes: &mut BytesMut, _eof: bool) -> Result<Frame, ParseError> {
+    if bytes.len() < 2 {
+        return Err(ParseError::Incomplete("Partial frame header"));
+    }
+
+    let masked = bytes[1] & MASKED != 0;
+    let (len, len_size) = match bytes[1] & 0x7f {
+        126 if bytes.len() >= 4 => (u16::from_be_bytes([bytes[2], bytes[3]]) as u64, 2),
+        127 if bytes.len() >= 10 => {
+            let mut len = [0; 8];
+
+            len.copy_from_slice(&bytes[2..10]);
+
+            // The most significant bit of the 64-bit length must be 0
+            if len[0] & 0x80 != 0 {
+                return Err(ParseError::Invalid("Invalid frame length".to_string()));
+            }
+
+            (u64::from_be_bytes(len), 8)
+        }
+        126 | 127 => return Err(ParseError::Incomplete("Partial frame header")),
+        len => (len as u64, 0),
+    };
+
+    let header_len = 2 + len_size + if masked { 4 } else { 0 };
+    let frame_len = (header_len as u64)
+        .checked_add(len)
+        .and_then(|frame_len| usize::try_from(frame_len).ok())
+        .ok_or_else(|| ParseError::Invalid("Frame is too large".to_string()))?;
+
+    if bytes.len() < frame_len {
+        return Err(ParseError::Incomplete("Partial frame"));
+    }
+
+    let header = bytes.split_to(header_len);
+    let mut payload = bytes.split_to(frame_len - header_len);
+    let first = header[0];
+
+    if masked {
+        let mask = &header[header_len - 4..];
+
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+
+    Ok(Frame {
+        fin: first & FIN != 0,
+        compressed: first & COMPRESSED != 0,
+        opcode: first & 0x0f,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Compress, Compression, FlushCompress};
+
+    use super::*;
+    use crate::stream::parse_response_head;
+
+    const HANDSHAKE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n\r\n";
+
+    fn connection() -> Connection {
+        let (response, _) = parse_response_head(HANDSHAKE).unwrap();
+
+        Connection::new(PendingExchange::new(None, 0), response)
+    }
+
+    fn frame(first: u8, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        let mask_bit = if mask.is_some() { MASKED } else { 0 };
+
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+
+        frame
+    }
+
+    fn deflate(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len() + 64);
+
+        compress
+            .compress_vec(data, &mut output, FlushCompress::Sync)
+            .unwrap();
+        assert!(output.ends_with(&DEFLATE_TAIL));
+        output.truncate(output.len() - DEFLATE_TAIL.len());
+        output
+    }
+
+    fn receive(connection: &mut Connection, from_client: bool, bytes: &[u8]) {
+        let mut stream = ByteStream::default();
+
+        stream.push(bytes);
+        connection.receive(from_client, &mut stream, Duration::ZERO);
+    }
+
+    #[test]
+    fn masked_and_fragmented_messages() {
+        let mut connection = connection();
+        let long = vec![b'x'; 300];
+        let mask = Some([0x37, 0xfa, 0x21, 0x3d]);
+
+        let mut bytes = frame(0x01, mask, b"Hel");
+        bytes.extend(frame(0x89, mask, b"ping"));
+        bytes.extend(frame(0x80, mask, b"lo"));
+        bytes.extend(frame(0x82, mask, &long));
+        bytes.extend(frame(0x88, mask, &1000u16.to_be_bytes()));
+        receive(&mut connection, true, &bytes);
+        assert!(connection.take_finished(false).is_none());
+
+        receive(&mut connection, false, &frame(0x88, None, &[]));
+
+        let (pending, _) = connection.take_finished(false).unwrap();
+        let messages = &pending.messages;
+
+        assert!(pending.error.is_none());
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            (messages[0].opcode, &messages[0].data[..]),
+            (Opcode::Ping, &b"ping"[..])
+        );
+        assert_eq!(
+            (messages[1].opcode, &messages[1].data[..]),
+            (Opcode::Text, &b"Hello"[..])
+        );
+        assert_eq!(messages[2].data, long);
+        assert_eq!(messages[3].opcode, Opcode::Close);
+        assert!(!messages[4].from_client);
+    }
+
+    #[test]
+    fn permessage_deflate() {
+        let mut connection = connection();
+        let mut client = Compress::new(Compression::default(), false);
+
+        // The client keeps its context, the server resets it for every message
+        let mut bytes = frame(0xc1, Some([1, 2, 3, 4]), &deflate(&mut client, b"Hello"));
+        bytes.extend(frame(
+            0xc1,
+            Some([5, 6, 7, 8]),
+            &deflate(&mut client, b"Hello"),
+        ));
+        receive(&mut connection, true, &bytes);
+
+        let mut bytes = vec![];
+
+        for _ in 0..2 {
+            let mut server = Compress::new(Compression::default(), false);
+
+            bytes.extend(frame(0xc1, None, &deflate(&mut server, b"World")));
+        }
+
+        bytes.extend(frame(0xc1, None, b"\xff\xff\xff"));
+        receive(&mut connection, false, &bytes);
+
+        let (pending, _) = connection.take_finished(true).unwrap();
+        let messages = &pending.messages;
+
+        assert_eq!(messages.len(), 5);
+
+        for (message, data) in messages
+            .iter()
+            .zip([b"Hello", b"Hello", b"World", b"World"])
+        {
+            assert!(message.compressed);
+            assert_eq!(message.error, None);
+            assert_eq!(message.data, data);
+        }
+
+        assert!(messages[4]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("Cannot inflate"));
+    }
+
+    #[test]
+    fn invalid_frame_length() {
+        let mut connection = connection();
+        let mut bytes = frame(0x81, None, b"hi");
+
+        bytes.extend([0x82, 127]);
+        bytes.extend(u64::MAX.to_be_bytes());
+        receive(&mut connection, false, &bytes);
+
+        let (pending, _) = connection.take_finished(false).unwrap();
+
+        assert_eq!(pending.messages.len(), 1);
+        assert_eq!(
+            pending.error.as_deref(),
+            Some("WebSocket decoding stopped: Invalid frame length")
+        );
+    }
+}
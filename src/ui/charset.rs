@@ -0,0 +1,156 @@
+// Decoding the text of bodies with their charset
+
+/// The charsets the bodies can be decoded with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    Utf8,
+    Latin1,
+    Windows1252,
+    Utf16Le,
+    Utf16Be,
+}
+
+/// The characters of the 0x80-0x9f range of Windows-1252. The undefined bytes are mapped to
+/// the C1 controls like ISO-8859-1 does.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+impl Charset {
+    /// The charsets in the order the user can switch between them.
+    pub const ALL: [Charset; 5] = [
+        Charset::Utf8,
+        Charset::Latin1,
+        Charset::Windows1252,
+        Charset::Utf16Le,
+        Charset::Utf16Be,
+    ];
+
+    /// Find the charset by its name in the `charset` parameter of Content-Type. ASCII is
+    /// decoded as Windows-1252 like browsers do, as it is often sent for Latin text.
+    pub fn from_label(label: &str) -> Option<Charset> {
+        let charset = match label.trim().to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" | "unicode-1-1-utf-8" => Charset::Utf8,
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" | "cp819" => {
+                Charset::Latin1
+            }
+            "windows-1252" | "cp1252" | "x-cp1252" | "us-ascii" | "ascii" => Charset::Windows1252,
+            "utf-16le" | "utf-16" | "unicode" => Charset::Utf16Le,
+            "utf-16be" => Charset::Utf16Be,
+            _ => return None,
+        };
+
+        Some(charset)
+    }
+
+    /// Find the charset from the byte order mark, with the length of the mark.
+    pub fn from_bom(data: &[u8]) -> Option<(Charset, usize)> {
+        match data {
+            [0xef, 0xbb, 0xbf, ..] => Some((Charset::Utf8, 3)),
+            [0xff, 0xfe, ..] => Some((Charset::Utf16Le, 2)),
+            [0xfe, 0xff, ..] => Some((Charset::Utf16Be, 2)),
+            _ => None,
+        }
+    }
+
+    /// Decode the text, the invalid sequences are replaced. The byte order mark is not
+    /// part of the text.
+    pub fn decode(&self, data: &[u8]) -> String {
+        let data = match Charset::from_bom(data) {
+            Some((charset, len)) if charset == *self => &data[len..],
+            _ => data,
+        };
+
+        match self {
+            Charset::Utf8 => String::from_utf8_lossy(data).to_string(),
+            Charset::Latin1 => data.iter().map(|b| *b as char).collect(),
+            Charset::Windows1252 => data
+                .iter()
+                .map(|b| match b {
+                    0x80..=0x9f => WINDOWS_1252[(b - 0x80) as usize],
+                    b => *b as char,
+                })
+                .collect(),
+            Charset::Utf16Le | Charset::Utf16Be => {
+                let units = data.chunks(2).map(|unit| match (unit, self) {
+                    ([low, high], Charset::Utf16Le) => u16::from_le_bytes([*low, *high]),
+                    ([high, low], _) => u16::from_be_bytes([*high, *low]),
+                    // The odd byte at the end is invalid
+                    _ => 0xdc00,
+                });
+
+                char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            }
+        }
+    }
+
+    /// The charset after this one when the user switches between them, `None` means
+    /// the charset is found out from the body.
+    pub fn next(charset: Option<Charset>) -> Option<Charset> {
+        match charset {
+            None => Some(Charset::ALL[0]),
+            Some(charset) => {
+                let i = Charset::ALL.iter().position(|c| *c == charset).unwrap_or(0);
+
+                Charset::ALL.get(i + 1).copied()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Charset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Charset::Utf8 => "UTF-8",
+            Charset::Latin1 => "ISO-8859-1",
+            Charset::Windows1252 => "Windows-1252",
+            Charset::Utf16Le => "UTF-16LE",
+            Charset::Utf16Be => "UTF-16BE",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// The text of a body and how its charset was found out.
+pub struct Decoded {
+    pub text: String,
+    pub charset: Charset,
+    /// Where the charset comes from, like `declared in Content-Type`.
+    pub source: &'static str,
+}
+
+/// Decode the text with the charset selected by the user, the one of the byte order mark,
+/// or the one declared in Content-Type, in this order. Without them the text needs to be
+/// valid UTF-8. Gives back `None` if the data is not printable text with the charset.
+pub fn decode_text(
+    data: &[u8],
+    declared: Option<&str>,
+    selected: Option<Charset>,
+) -> Option<Decoded> {
+    let (charset, source) = match (selected, Charset::from_bom(data)) {
+        (Some(charset), _) => (charset, "selected"),
+        (None, Some((charset, _))) => (charset, "from the byte order mark"),
+        (None, None) => match declared.and_then(Charset::from_label) {
+            Some(charset) => (charset, "declared in Content-Type"),
+            None => {
+                std::str::from_utf8(data).ok()?;
+
+                (Charset::Utf8, "the default")
+            }
+        },
+    };
+
+    let text = charset.decode(data);
+
+    text.chars()
+        .all(|c| !c.is_control() || c.is_whitespace())
+        .then_some(Decoded {
+            text,
+            charset,
+            source,
+        })
+}
@@ -1,4 +1,9 @@
-use ratatui::{layout::Rect, Frame};
+use crossterm::event::KeyCode;
+use ratatui::{
+    layout::{Alignment, Constraint, Layout, Rect},
+    widgets::{Block, BorderType, Borders, Clear, Padding, Paragraph},
+    Frame,
+};
 
 /// Center a Rect on the current Frame.
 pub fn center_rect(f: &Frame, width: u16, height: u16) -> Rect {
@@ -7,3 +12,69 @@ pub fn center_rect(f: &Frame, width: u16, height: u16) -> Rect {
 
     Rect::new(horizontal_margin, vertical_margin, width, height)
 }
+
+/// Apply the editing keys on a single line text input.
+pub fn edit_text(text: &mut String, key_code: KeyCode) {
+    match key_code {
+        KeyCode::Backspace => {
+            text.pop();
+        }
+        KeyCode::Char(c) => {
+            text.push(c);
+        }
+        _ => (),
+    }
+}
+
+/// Draw a dialog with a hint line and a single line text input.
+pub fn input_dialog(f: &mut Frame, title: &str, hint: &str, input: &str) {
+    let rect = center_rect(f, 70, 7);
+
+    let dialog = Block::default()
+        .title(title)
+        .title_alignment(Alignment::Center)
+        .borders(Borders::ALL)
+        .padding(Padding::horizontal(1));
+
+    let dialog_layout = Layout::default()
+        .constraints(vec![Constraint::Length(2), Constraint::Length(3)])
+        .split(dialog.inner(rect));
+
+    let input = Paragraph::new(format!("{}_", input)).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(BorderType::Plain),
+    );
+
+    f.render_widget(Clear, rect);
+    f.render_widget(dialog, rect);
+    f.render_widget(Paragraph::new(hint), dialog_layout[0]);
+    f.render_widget(input, dialog_layout[1]);
+}
+
+/// Format the bytes as hex dump lines, 16 bytes per line with the offset and the printable
+/// characters.
+pub fn hexdump(data: &[u8]) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex = chunk
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = chunk
+                .iter()
+                .map(|b| {
+                    if (32..127).contains(b) {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+
+            format!("{:08X}  {:<47}  |{}|", i * 16, hex, ascii)
+        })
+        .collect()
+}
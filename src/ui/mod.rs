@@ -1,6 +1,8 @@
+mod charset;
 mod common;
 pub mod stream;
 
+use chrono::Local;
 use crossbeam::{
     channel::{self, Receiver, Sender},
     select,
@@ -11,7 +13,7 @@ use crossterm::event::{
     KeyCode,
 };
 use log::error;
-use pcap::Device;
+use pcap::{Device, Linktype};
 use ratatui::{
     backend::Backend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -23,25 +25,35 @@ use ratatui::{
     },
     Frame, Terminal,
 };
-use std::{error::Error, fs::File, io::BufWriter, thread};
+use std::{
+    collections::HashMap, error::Error, fs::File, io::BufWriter, path::PathBuf, sync::Arc, thread,
+};
 
-use crate::capture_control::Command;
+use crate::{
+    capture::{self, CaptureError, CaptureSource},
+    capture_control::Command,
+    config::{self, CaptureConfig, Overrides},
+    savefile::{self, RawPacket, SaveFormat},
+};
 
-use self::stream::HttpStream;
+use self::{
+    charset::Charset,
+    stream::{HttpStream, Packets},
+};
 
 const HELP: &str = r#"
-c:        Start capture
-s:        Stop capture
+c:        Start/stop capture on a device
+o:        Open capture file
+e:        Edit capture ports
+f:        Edit capture filter
+s:        Stop all captures
 p:        Save current stream to file
+w:        Save packets of current stream
+W:        Save packets of all streams
+C:        Switch the charset of the bodies (details)
 q:        Quit
 "#;
 
-#[derive(Copy, Clone, PartialEq)]
-pub enum CaptureState {
-    Active,
-    Inactive,
-}
-
 /// Tells which window is currently on focus.
 #[derive(Copy, Clone, PartialEq)]
 pub enum SelectedWindow {
@@ -49,68 +61,139 @@ pub enum SelectedWindow {
     PacketDetails,
     Help,
     DeviceChooser,
+    FileChooser,
+    PortSetting,
     FilterSetting,
 }
 
 pub struct State {
-    input: Receiver<HttpStream>,
+    input: Receiver<capture::Event>,
     commands: Sender<Command>,
-    capture_state: CaptureState,
+    /// Names of the interfaces and files being captured
+    active_captures: Vec<String>,
+    /// The id of the last capture started on the interfaces and files
+    capture_ids: HashMap<String, usize>,
+    next_capture_id: usize,
+    /// The number of connections evicted from the flow tables by the captures
+    evicted: HashMap<String, usize>,
     selected_window: SelectedWindow,
     streams: Vec<HttpStream>,
     stream_items: Vec<Row<'static>>,
+    /// The index of the exchanges in progress by their capture, connection and number,
+    /// their updates replace them in the list.
+    live_streams: HashMap<(usize, usize, usize), usize>,
     selected_stream: TableState,
     details_scroll: (u16, u16),
-    devices: Vec<ListItem<'static>>,
+    devices: Vec<String>,
     device_names: Vec<String>,
     selected_device: ListState,
+    file_path: String,
+    /// The config loaded from the config file and edited in the UI, this is what is saved.
+    config: CaptureConfig,
+    /// The settings of the command line, they are not saved.
+    overrides: Overrides,
+    ports_input: String,
+    filter_input: String,
+    /// The link types of the sources captured in this run, the filter is checked against
+    /// them.
+    datalinks: HashMap<String, Linktype>,
+    save_format: SaveFormat,
     status_line: String,
 }
 
 impl State {
-    pub fn new(input: Receiver<HttpStream>, cmd: Sender<Command>) -> State {
+    pub fn new(
+        input: Receiver<capture::Event>,
+        cmd: Sender<Command>,
+        config: CaptureConfig,
+        overrides: Overrides,
+    ) -> State {
         let (devices, device_names) = device_list();
 
         State {
             input,
             commands: cmd,
-            capture_state: CaptureState::Inactive,
+            active_captures: vec![],
+            capture_ids: HashMap::new(),
+            next_capture_id: 0,
+            evicted: HashMap::new(),
             selected_window: SelectedWindow::PacketList,
             streams: vec![],
             stream_items: vec![],
+            live_streams: HashMap::new(),
             selected_stream: TableState::default(),
             details_scroll: (0, 0),
             devices,
             device_names,
             selected_device: ListState::default(),
+            file_path: String::from(""),
+            config,
+            overrides,
+            ports_input: String::from(""),
+            filter_input: String::from(""),
+            datalinks: HashMap::new(),
+            save_format: SaveFormat::Pcap,
             status_line: String::from(""),
         }
     }
 
     fn handle_event(&mut self, event: Event) -> bool {
         if let Key(key) = event {
+            // Text input windows get every key, global shortcuts would eat the letters
+            match self.selected_window {
+                SelectedWindow::FileChooser => {
+                    self.handle_key_file_widget(key.code);
+
+                    return true;
+                }
+                SelectedWindow::PortSetting => {
+                    self.handle_key_ports_widget(key.code);
+
+                    return true;
+                }
+                SelectedWindow::FilterSetting => {
+                    self.handle_key_filter_widget(key.code);
+
+                    return true;
+                }
+                _ => {}
+            }
+
             // Global key shortcuts
             match key.code {
                 KeyCode::Char('q') => {
-                    if self.capture_state == CaptureState::Active {
-                        self.commands.send(Command::StopCapture).unwrap();
+                    if self.is_capturing() {
+                        self.commands.send(Command::StopAllCaptures).unwrap();
                     }
 
                     return false;
                 }
                 KeyCode::Char('c') => {
-                    if self.capture_state == CaptureState::Inactive {
-                        self.set_selected_window(SelectedWindow::DeviceChooser);
+                    self.set_selected_window(SelectedWindow::DeviceChooser);
 
-                        return true;
-                    }
+                    return true;
                 }
-                KeyCode::Char('s') => {
-                    if self.capture_state == CaptureState::Active {
-                        self.set_capture_state(CaptureState::Inactive);
-                        self.commands.send(Command::StopCapture).unwrap();
-                        self.status_line = String::from("Stop capturing");
-                    }
+                KeyCode::Char('o') => {
+                    self.set_selected_window(SelectedWindow::FileChooser);
+
+                    return true;
+                }
+                KeyCode::Char('e') => {
+                    self.ports_input = config::format_ports(&self.capture_config().ports);
+                    self.set_selected_window(SelectedWindow::PortSetting);
+
+                    return true;
+                }
+                KeyCode::Char('f') => {
+                    self.filter_input = self.capture_config().filter.unwrap_or_default();
+                    self.set_selected_window(SelectedWindow::FilterSetting);
+
+                    return true;
+                }
+                KeyCode::Char('s') if self.is_capturing() => {
+                    self.active_captures.clear();
+                    self.commands.send(Command::StopAllCaptures).unwrap();
+                    self.status_line = String::from("Stop capturing");
                 }
                 KeyCode::Char('h') => {
                     self.set_selected_window(SelectedWindow::Help);
@@ -149,6 +232,12 @@ impl State {
             KeyCode::Char('p') => {
                 self.save_http_stream();
             }
+            KeyCode::Char('w') => {
+                self.save_stream_packets();
+            }
+            KeyCode::Char('W') => {
+                self.save_all_packets();
+            }
             KeyCode::Tab => self.set_selected_window(SelectedWindow::PacketDetails),
             _ => {}
         }
@@ -161,6 +250,7 @@ impl State {
             KeyCode::Down => self.scroll_down(),
             KeyCode::PageUp => self.scroll_page_up(),
             KeyCode::PageDown => self.scroll_page_down(),
+            KeyCode::Char('C') => self.switch_charset(),
             _ => {}
         }
     }
@@ -179,17 +269,181 @@ impl State {
             KeyCode::Enter | KeyCode::Char(' ') => {
                 if let Some(dev) = self.get_selected_device_name() {
                     self.set_selected_window(SelectedWindow::PacketList);
-                    self.set_capture_state(CaptureState::Active);
-                    self.commands
-                        .send(Command::StartCapture(dev.to_string()))
-                        .unwrap();
-                    self.status_line = format!("Start capturing on device {}", dev);
+
+                    // Choosing a device which is being captured stops its capture
+                    if self.active_captures.contains(&dev) {
+                        self.stop_capture(dev);
+                    } else {
+                        self.start_capture(CaptureSource::Device(dev));
+                    }
                 }
             }
             _ => (),
         }
     }
 
+    fn handle_key_file_widget(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Esc => {
+                self.set_selected_window(SelectedWindow::PacketList);
+            }
+            KeyCode::Enter if !self.file_path.is_empty() => {
+                let path = PathBuf::from(self.file_path.trim());
+
+                self.set_selected_window(SelectedWindow::PacketList);
+                self.start_capture(CaptureSource::File(path));
+            }
+            _ => common::edit_text(&mut self.file_path, key_code),
+        }
+    }
+
+    fn handle_key_ports_widget(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Esc => {
+                self.set_selected_window(SelectedWindow::PacketList);
+            }
+            KeyCode::Enter => match config::parse_ports(&self.ports_input) {
+                Ok(ports) => {
+                    // The ports set in the UI are used instead of the ones of the command line
+                    self.config.ports = ports;
+                    self.overrides.ports = None;
+                    self.set_selected_window(SelectedWindow::PacketList);
+
+                    self.status_line = match self.config.save() {
+                        Ok(()) => format!(
+                            "Capture ports: {}",
+                            config::format_ports(&self.config.ports)
+                        ),
+                        Err(e) => format!("Error saving config: {}", e),
+                    };
+                }
+                Err(e) => {
+                    self.status_line = e;
+                }
+            },
+            _ => common::edit_text(&mut self.ports_input, key_code),
+        }
+    }
+
+    fn handle_key_filter_widget(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Esc => {
+                self.set_selected_window(SelectedWindow::PacketList);
+            }
+            KeyCode::Enter => {
+                let filter = self.filter_input.trim();
+
+                let datalinks = self.datalinks.values().copied().collect::<Vec<_>>();
+
+                if filter.is_empty() {
+                    self.config.filter = None;
+                } else if let Err(e) = capture::check_filter(filter, &datalinks) {
+                    self.status_line = format!("Invalid filter: {}", e);
+
+                    return;
+                } else {
+                    self.config.filter = Some(filter.to_string());
+                }
+
+                self.overrides.filter = None;
+
+                self.set_selected_window(SelectedWindow::PacketList);
+
+                self.status_line = match self.config.save() {
+                    Ok(()) => format!("Capture filter: {}", self.capture_config().bpf_filter()),
+                    Err(e) => format!("Error saving config: {}", e),
+                };
+            }
+            _ => common::edit_text(&mut self.filter_input, key_code),
+        }
+    }
+
+    /// Send the start capture command to the capture control and mark the capture active.
+    pub fn start_capture(&mut self, source: CaptureSource) {
+        self.status_line = match source {
+            CaptureSource::Device(ref dev) => format!("Start capturing on device {}", dev),
+            CaptureSource::File(ref path) => format!("Reading capture file {}", path.display()),
+        };
+
+        self.evicted.remove(&source.to_string());
+
+        if !self.active_captures.contains(&source.to_string()) {
+            self.active_captures.push(source.to_string());
+        }
+
+        self.next_capture_id += 1;
+        self.capture_ids
+            .insert(source.to_string(), self.next_capture_id);
+
+        self.commands
+            .send(Command::StartCapture(
+                source,
+                self.capture_config(),
+                self.next_capture_id,
+            ))
+            .unwrap();
+    }
+
+    /// The config of the captures, the saved one with the command line settings.
+    fn capture_config(&self) -> CaptureConfig {
+        self.overrides.apply(&self.config)
+    }
+
+    /// Stop the capture of one device or file.
+    pub fn stop_capture(&mut self, name: String) {
+        self.active_captures.retain(|c| *c != name);
+        self.status_line = format!("Stop capturing on {}", name);
+        self.commands.send(Command::StopCapture(name)).unwrap();
+    }
+
+    fn is_capturing(&self) -> bool {
+        !self.active_captures.is_empty()
+    }
+
+    /// Handle the events coming from the capture threads.
+    pub fn handle_capture_event(&mut self, event: capture::Event) {
+        match event {
+            capture::Event::Stream(stream) => self.add_stream(*stream),
+            capture::Event::Evicted { source, count } => {
+                self.evicted.insert(source, count);
+            }
+            capture::Event::Started {
+                source,
+                capture,
+                datalink,
+            } => {
+                if self.capture_ids.get(&source) == Some(&capture) {
+                    self.datalinks.insert(source, datalink);
+                }
+            }
+            capture::Event::Finished {
+                source,
+                capture,
+                error,
+            } => {
+                self.finish_live_streams(capture);
+
+                // A capture restarted on the same source is running, this one was replaced
+                if self.capture_ids.get(&source) != Some(&capture) {
+                    return;
+                }
+
+                self.active_captures.retain(|c| *c != source);
+
+                if let Some(CaptureError::Filter(_)) = error {
+                    // Let the user fix the filter which cannot be used on this source
+                    self.filter_input = self.capture_config().filter.unwrap_or_default();
+                    self.set_selected_window(SelectedWindow::FilterSetting);
+                }
+
+                self.status_line = match error {
+                    None => format!("Capture on {} finished", source),
+                    Some(e) => format!("Capture on {} failed: {}", source, e),
+                };
+            }
+        }
+    }
+
     fn draw_ui(&mut self, f: &mut Frame) {
         let parent_chunk = Layout::default()
             .direction(Direction::Vertical)
@@ -210,6 +464,9 @@ impl State {
         match self.selected_window {
             SelectedWindow::Help => help(f),
             SelectedWindow::DeviceChooser => self.devices_draw_ui(f),
+            SelectedWindow::FileChooser => self.file_draw_ui(f),
+            SelectedWindow::PortSetting => self.ports_draw_ui(f),
+            SelectedWindow::FilterSetting => self.filter_draw_ui(f),
             _ => (),
         }
     }
@@ -222,13 +479,24 @@ impl State {
             BorderType::Plain
         };
 
-        let title = if self.capture_state == CaptureState::Active {
+        let evicted: usize = self.evicted.values().sum();
+        let evicted = if evicted > 0 {
+            format!(" - {} connections evicted", evicted)
+        } else {
+            String::new()
+        };
+
+        let title = if self.is_capturing() {
             Span::styled(
-                "HTTP streams (capturing)",
+                format!(
+                    "HTTP streams (capturing on {}){}",
+                    self.active_captures.join(", "),
+                    evicted
+                ),
                 Style::default().add_modifier(Modifier::BOLD),
             )
         } else {
-            Span::raw("HTTP streams")
+            Span::raw(format!("HTTP streams{}", evicted))
         };
 
         let stream_list = Table::new(
@@ -236,14 +504,22 @@ impl State {
             vec![
                 Constraint::Length(6),
                 Constraint::Length(12),
+                Constraint::Length(8),
                 Constraint::Length(20),
                 Constraint::Length(20),
                 Constraint::Fill(1),
             ],
         )
         .header(
-            Row::new(vec!["Seq", "Timestamp", "Source", "Destination", "Path"])
-                .style(Style::new().fg(Color::White).add_modifier(Modifier::BOLD)),
+            Row::new(vec![
+                "Seq",
+                "Timestamp",
+                "Interface",
+                "Source",
+                "Destination",
+                "Path",
+            ])
+            .style(Style::new().fg(Color::White).add_modifier(Modifier::BOLD)),
         )
         .block(
             Block::default()
@@ -252,7 +528,7 @@ impl State {
                 .borders(Borders::ALL)
                 .border_type(border_type),
         )
-        .highlight_style(
+        .row_highlight_style(
             Style::new()
                 .bg(Color::LightGreen)
                 .fg(Color::Black)
@@ -302,7 +578,23 @@ impl State {
             .constraints(vec![Constraint::Min(2), Constraint::Percentage(100)])
             .split(dialog.inner(rect));
 
-        let devices = List::new(self.devices.clone())
+        // Devices being captured are marked with a star
+        let items: Vec<ListItem> = self
+            .devices
+            .iter()
+            .zip(&self.device_names)
+            .map(|(label, name)| {
+                let mark = if self.active_captures.contains(name) {
+                    "*"
+                } else {
+                    " "
+                };
+
+                ListItem::new(format!("{} {}", mark, label))
+            })
+            .collect();
+
+        let devices = List::new(items)
             .block(
                 Block::default()
                     .title("Choose device")
@@ -316,18 +608,41 @@ impl State {
         f.render_widget(Clear, rect);
         f.render_widget(dialog, rect);
         f.render_widget(
-            Paragraph::new("Choose a device and press Enter"),
+            Paragraph::new("Choose a device and press Enter to start or stop capturing on it"),
             dialog_layout[0],
         );
         f.render_stateful_widget(devices, dialog_layout[1], &mut self.selected_device);
     }
 
-    fn set_selected_window(&mut self, s: SelectedWindow) {
-        self.selected_window = s;
+    pub fn file_draw_ui(&mut self, f: &mut Frame) {
+        common::input_dialog(
+            f,
+            "Open capture file",
+            "Type the path of a pcap/pcapng file and press Enter",
+            &self.file_path,
+        );
+    }
+
+    pub fn filter_draw_ui(&mut self, f: &mut Frame) {
+        common::input_dialog(
+            f,
+            "Capture filter",
+            "BPF expression used instead of the ports, leave it empty to use the ports",
+            &self.filter_input,
+        );
+    }
+
+    pub fn ports_draw_ui(&mut self, f: &mut Frame) {
+        common::input_dialog(
+            f,
+            "Capture ports",
+            "Comma separated ports and port ranges, e.g. 80, 8080, 3000-3010",
+            &self.ports_input,
+        );
     }
 
-    fn set_capture_state(&mut self, c: CaptureState) {
-        self.capture_state = c;
+    fn set_selected_window(&mut self, s: SelectedWindow) {
+        self.selected_window = s;
     }
 
     pub fn reset_scroll(&mut self) {
@@ -356,10 +671,87 @@ impl State {
         self.details_scroll.0 += 15;
     }
 
-    /// Add a new RawStream to the UI and convert it to a HttpStream.
-    pub fn add_stream(&mut self, stream: HttpStream) {
-        self.stream_items.push((&stream).into());
-        self.streams.push(stream);
+    /// Add the exchange to the list, or update it if it was added while in progress.
+    pub fn add_stream(&mut self, mut stream: HttpStream) {
+        let key = (stream.capture, stream.connection, stream.id);
+        let in_progress = stream.in_progress;
+
+        let index = match self.live_streams.get(&key) {
+            Some(&index) => {
+                let old = &mut self.streams[index];
+
+                // Keep the charset the user selected while the exchange was in progress
+                stream.charset = old.charset;
+
+                if in_progress {
+                    // The update has only the new messages and events
+                    let mut messages = std::mem::take(&mut old.messages);
+                    let mut sse_events = std::mem::take(&mut old.sse_events);
+
+                    messages.append(&mut stream.messages);
+                    sse_events.append(&mut stream.sse_events);
+                    stream.messages = messages;
+                    stream.sse_events = sse_events;
+                }
+
+                self.stream_items[index] = (&stream).into();
+                self.streams[index] = stream;
+
+                index
+            }
+            None => {
+                self.stream_items.push((&stream).into());
+                self.streams.push(stream);
+
+                self.streams.len() - 1
+            }
+        };
+
+        if in_progress {
+            self.live_streams.insert(key, index);
+        } else {
+            self.live_streams.remove(&key);
+        }
+    }
+
+    /// Decode the bodies of the selected exchange with the next charset, after the last
+    /// one the charset is found out from the bodies again.
+    fn switch_charset(&mut self) {
+        let Some(stream) = self
+            .selected_stream
+            .selected()
+            .and_then(|selected| self.streams.get_mut(selected))
+        else {
+            return;
+        };
+
+        stream.charset = Charset::next(stream.charset);
+
+        self.status_line = match stream.charset {
+            Some(charset) => format!("Charset: {charset}"),
+            None => "Charset: automatic".to_string(),
+        };
+    }
+
+    /// The exchanges still in progress when their capture finished won't be updated, they
+    /// are left as they are.
+    fn finish_live_streams(&mut self, capture: usize) {
+        self.live_streams
+            .retain(|&(live_capture, _, _), &mut index| {
+                if live_capture != capture {
+                    return true;
+                }
+
+                let stream = &mut self.streams[index];
+
+                stream.in_progress = false;
+                stream.error.get_or_insert(
+                    "The capture finished before the response was complete".to_string(),
+                );
+                self.stream_items[index] = (&*stream).into();
+
+                false
+            });
     }
 
     pub fn save_http_stream(&mut self) {
@@ -378,6 +770,52 @@ impl State {
         }
     }
 
+    pub fn set_save_format(&mut self, format: SaveFormat) {
+        self.save_format = format;
+    }
+
+    /// Save the packets of the tcp connection of the selected stream.
+    pub fn save_stream_packets(&mut self) {
+        if let Some(selected) = &self.selected_stream.selected() {
+            if let Some(selected_stream) = self.streams.get(*selected) {
+                let file_name = format!(
+                    "http-stream-{}.{}",
+                    selected_stream.id,
+                    self.save_format.extension()
+                );
+                let packets = selected_stream.packets.read().unwrap();
+                let packets: Vec<&RawPacket> = packets.iter().collect();
+
+                self.status_line = write_packets(file_name, self.save_format, &packets);
+            }
+        }
+    }
+
+    /// Save the packets of all the streams in the list into one file.
+    pub fn save_all_packets(&mut self) {
+        let mut connections: Vec<&Packets> = vec![];
+
+        // Streams of the same tcp connection share their packets
+        for stream in &self.streams {
+            if !connections.iter().any(|c| Arc::ptr_eq(c, &stream.packets)) {
+                connections.push(&stream.packets);
+            }
+        }
+
+        let connections: Vec<_> = connections.iter().map(|c| c.read().unwrap()).collect();
+        let mut packets: Vec<&RawPacket> = connections.iter().flat_map(|c| c.iter()).collect();
+
+        packets.sort_by_key(|p| p.timestamp);
+
+        let file_name = format!(
+            "http-streams-{}.{}",
+            Local::now().format("%Y%m%d-%H%M%S"),
+            self.save_format.extension()
+        );
+
+        self.status_line = write_packets(file_name, self.save_format, &packets);
+    }
+
     pub fn get_selected_device_name(&self) -> Option<String> {
         if let Some(selected) = self.selected_device.selected() {
             let dev = self.device_names.get(selected).unwrap();
@@ -389,7 +827,15 @@ impl State {
     }
 }
 
-fn device_list() -> (Vec<ListItem<'static>>, Vec<String>) {
+/// Write the packets to a capture file and give back the status message of the result.
+fn write_packets(file_name: String, format: SaveFormat, packets: &[&RawPacket]) -> String {
+    match savefile::write_packets(file_name.as_ref(), format, packets) {
+        Ok(()) => format!("{} packets saved to {}", packets.len(), file_name),
+        Err(e) => format!("Error saving packets: {}", e),
+    }
+}
+
+fn device_list() -> (Vec<String>, Vec<String>) {
     let devices = Device::list()
         .unwrap_or_default()
         .into_iter()
@@ -400,7 +846,7 @@ fn device_list() -> (Vec<ListItem<'static>>, Vec<String>) {
                 .map(|a| a.addr.to_string())
                 .unwrap_or_default();
 
-            ListItem::new(format!("{:7} - {}", d.name, addr))
+            format!("{:7} - {}", d.name, addr)
         })
         .collect();
 
@@ -443,9 +889,9 @@ pub fn run_app<B: Backend>(
                     error!("Error in run_app recv: {e:?}");
                 }
             },
-            recv(state.input) -> stream => match stream {
-                Ok(stream) => {
-                    state.add_stream(stream);
+            recv(state.input) -> event => match event {
+                Ok(event) => {
+                    state.handle_capture_event(event);
                 },
                 Err(e) => {
                     error!("Error in run_app loop: {e:?}");
@@ -498,7 +944,7 @@ fn move_down(pos: Option<usize>, inc: usize, len: usize) -> Option<usize> {
 }
 
 fn help(f: &mut Frame) {
-    let rect = common::center_rect(f, 30, 10);
+    let rect = common::center_rect(f, 42, 14);
 
     let help = Paragraph::new(HELP).block(
         Block::default()
@@ -1,42 +1,483 @@
-use std::{collections::HashMap, fs::File, io::Write, net::IpAddr};
+use std::{
+    fs::File,
+    io::Write,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
+use chrono::{DateTime, Local};
 use ratatui::{
     style::{Color, Modifier, Style},
     text::{Line, Span, Text},
     widgets::Row,
 };
 
+use super::{
+    charset::{self, Charset},
+    common,
+};
+use crate::savefile::RawPacket;
+
+/// Binary bodies are shown as hex dump up to this size.
+const MAX_HEXDUMP_BYTES: usize = 64 * 1024;
+/// The parts of multipart forms are shown up to this size.
+const MAX_PREVIEW_BYTES: usize = 1024;
+
+/// The packets of a tcp connection, shared by the exchanges sent on it. The packets of an
+/// exchange in progress are appended while it is shown.
+pub type Packets = Arc<RwLock<Vec<RawPacket>>>;
+
 pub struct HttpStream {
+    /// The number of the exchange on its connection.
     pub id: usize,
+    /// The connection of the exchange, unique within the capture.
+    pub connection: usize,
+    /// The interface or capture file the stream was captured on.
+    pub interface: String,
+    /// The id of the capture, a restarted capture numbers its connections from 0 again.
+    pub capture: usize,
     pub timestamp: i64,
     pub source_addr: IpAddr,
     pub source_port: u16,
     pub dest_addr: IpAddr,
     pub dest_port: u16,
     pub parsed_request: Option<Req>,
+    /// Informational (1xx) responses sent before the final response.
+    pub interim_responses: Vec<Resp>,
     pub parsed_response: Option<Resp>,
+    /// The number of bytes of the request and response missing from the capture.
+    pub missing_bytes: u64,
+    /// Why the request or the response could not be parsed, or the response is missing.
+    pub error: Option<String>,
+    /// The bytes of the request which could not be parsed.
+    pub unparsed_request: Vec<u8>,
+    /// The bytes of the response which could not be parsed.
+    pub unparsed_response: Vec<u8>,
+    /// Protocol events of the exchange, like resetting the HTTP/2 stream.
+    pub events: Vec<String>,
+    /// The WebSocket messages sent after the handshake of this exchange.
+    pub messages: Vec<WsMessage>,
+    /// The traffic of the tunnel opened by a CONNECT request.
+    pub tunnel: Option<Tunnel>,
+    /// The events of a `text/event-stream` response.
+    pub sse_events: Vec<SseEvent>,
+    /// The messages of a gRPC call, sent by the client and by the server.
+    pub grpc_messages: Vec<GrpcMessage>,
+    /// The status of a gRPC call from the `grpc-status` and `grpc-message` fields.
+    pub grpc_status: Option<GrpcStatus>,
+    /// The response is still being received, the exchange is emitted again with the rest.
+    /// The updates in progress carry only the WebSocket messages and server-sent events
+    /// received since the previous update.
+    pub in_progress: bool,
+    /// The charset of the bodies selected by the user, otherwise it is found out from the
+    /// byte order mark or the Content-Type of the bodies.
+    pub charset: Option<Charset>,
+    /// The packets of the tcp connection the request-response pair was sent on.
+    pub packets: Packets,
 }
 
+#[derive(Clone)]
 pub struct Req {
     pub method: String,
+    /// The path of the request target, or the host and port of a CONNECT request.
     pub path: String,
+    /// The host of an absolute-form request target, like `http://host/path` sent to proxies.
+    pub authority: Option<String>,
     pub version: String,
-    pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    pub headers: Headers,
+    pub body: Option<Body>,
+    /// Trailer fields sent after a chunked body.
+    pub trailers: Headers,
+    /// The body is shorter than it should be, the rest was not captured.
+    pub truncated: bool,
 }
 
+#[derive(Clone)]
 pub struct Resp {
     pub version: String,
     pub code: u16,
     pub reason: Option<String>,
-    pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    pub headers: Headers,
+    pub body: Option<Body>,
+    /// Trailer fields sent after a chunked body.
+    pub trailers: Headers,
+    /// The body is shorter than it should be, the rest was not captured.
+    pub truncated: bool,
+    /// The body has no length, it ended when the server closed the connection.
+    pub close_delimited: bool,
+}
+
+/// Header fields in the order they were sent, with their original case. A field name can
+/// occur more than once, like `Set-Cookie`.
+#[derive(Clone, Debug, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn push(&mut self, name: String, value: String) {
+        self.0.push((name, value));
+    }
+
+    /// The value of the first field with the name, the name is case insensitive.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The values of all the fields with the name.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a (String, String);
+    type IntoIter = std::slice::Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl FromIterator<(String, String)> for Headers {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Headers(iter.into_iter().collect())
+    }
+}
+
+/// The bytes sent through a CONNECT tunnel, they are not parsed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tunnel {
+    pub client_bytes: u64,
+    pub server_bytes: u64,
+    /// The time from the response opening the tunnel till the last packet.
+    pub duration: Duration,
+}
+
+impl std::fmt::Display for Tunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tunnel {} bytes sent, {} bytes received in {:.1}s",
+            self.client_bytes,
+            self.server_bytes,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+/// A WebSocket message, the payload of its frames joined and decompressed.
+#[derive(Clone)]
+pub struct WsMessage {
+    pub from_client: bool,
+    /// The time of the packet which completed the first frame.
+    pub timestamp: Duration,
+    pub opcode: Opcode,
+    pub data: Vec<u8>,
+    /// The message was compressed with permessage-deflate.
+    pub compressed: bool,
+    /// The message is not complete or it could not be decompressed.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Opcode {
+    /// Control frames cannot be fragmented, they can be sent between the frames of a
+    /// message.
+    pub fn is_control(&self) -> bool {
+        match self {
+            Opcode::Close | Opcode::Ping | Opcode::Pong => true,
+            Opcode::Reserved(opcode) => *opcode >= 0x8,
+            _ => false,
+        }
+    }
+}
+
+impl From<u8> for Opcode {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0x0 => Opcode::Continuation,
+            0x1 => Opcode::Text,
+            0x2 => Opcode::Binary,
+            0x8 => Opcode::Close,
+            0x9 => Opcode::Ping,
+            0xa => Opcode::Pong,
+            _ => Opcode::Reserved(opcode),
+        }
+    }
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opcode::Continuation => write!(f, "Continuation"),
+            Opcode::Text => write!(f, "Text"),
+            Opcode::Binary => write!(f, "Binary"),
+            Opcode::Close => write!(f, "Close"),
+            Opcode::Ping => write!(f, "Ping"),
+            Opcode::Pong => write!(f, "Pong"),
+            Opcode::Reserved(opcode) => write!(f, "Opcode 0x{opcode:x}"),
+        }
+    }
+}
+
+impl WsMessage {
+    /// The header line of the message with the time, direction, opcode and size.
+    fn title(&self) -> String {
+        let time = format_time(self.timestamp);
+        let direction = if self.from_client {
+            "client -> server"
+        } else {
+            "server -> client"
+        };
+
+        let mut title = format!(
+            "{time} {direction} {} ({} bytes",
+            self.opcode,
+            self.data.len()
+        );
+
+        if self.compressed {
+            title.push_str(", compressed");
+        }
+
+        title.push(')');
+        title
+    }
+
+    /// The status code and the reason of a close message.
+    fn close_reason(&self) -> Option<String> {
+        if self.opcode != Opcode::Close || self.data.len() < 2 {
+            return None;
+        }
+
+        let code = u16::from_be_bytes([self.data[0], self.data[1]]);
+
+        Some(format!(
+            "{code} {}",
+            String::from_utf8_lossy(&self.data[2..])
+        ))
+    }
+}
+
+/// A server-sent event of a `text/event-stream` response.
+#[derive(Clone, Debug, Default)]
+pub struct SseEvent {
+    /// The time of the packet which completed the event.
+    pub timestamp: Duration,
+    /// The last event ID set by the server when the event was sent. The client sends it
+    /// back when it reconnects.
+    pub id: Option<String>,
+    /// The type of the event, it is `message` if the server didn't set it.
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl SseEvent {
+    /// The header line of the event with the time, type and ID.
+    fn title(&self) -> String {
+        let mut title = format!(
+            "{} {}",
+            format_time(self.timestamp),
+            self.event.as_deref().unwrap_or("message")
+        );
+
+        if let Some(ref id) = self.id {
+            title.push_str(&format!(" (id {id})"));
+        }
+
+        title
+    }
+}
+
+/// A message of a gRPC call, decoded from protobuf.
+#[derive(Clone, Debug, Default)]
+pub struct GrpcMessage {
+    pub from_client: bool,
+    /// The message was compressed with the `grpc-encoding` of the sender.
+    pub compressed: bool,
+    /// The message after decompression.
+    pub data: Vec<u8>,
+    /// The type of the message in the descriptor set, it is decoded to JSON then.
+    pub message_type: Option<String>,
+    /// The message as JSON, or in the protobuf wire format if its type is not known.
+    pub decoded: Option<String>,
+    pub error: Option<String>,
+}
+
+impl GrpcMessage {
+    /// The header line of the message with the direction, type and size.
+    fn title(&self) -> String {
+        let direction = if self.from_client {
+            "client -> server"
+        } else {
+            "server -> client"
+        };
+
+        let mut title = format!(
+            "{direction} {} ({} bytes",
+            self.message_type.as_deref().unwrap_or("message"),
+            self.data.len()
+        );
+
+        if self.compressed {
+            title.push_str(", compressed");
+        }
+
+        title.push(')');
+        title
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GrpcStatus {
+    pub code: u32,
+    pub message: Option<String>,
+}
+
+impl GrpcStatus {
+    pub fn is_ok(&self) -> bool {
+        self.code == 0
+    }
+}
+
+impl std::fmt::Display for GrpcStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const NAMES: [&str; 17] = [
+            "OK",
+            "CANCELLED",
+            "UNKNOWN",
+            "INVALID_ARGUMENT",
+            "DEADLINE_EXCEEDED",
+            "NOT_FOUND",
+            "ALREADY_EXISTS",
+            "PERMISSION_DENIED",
+            "RESOURCE_EXHAUSTED",
+            "FAILED_PRECONDITION",
+            "ABORTED",
+            "OUT_OF_RANGE",
+            "UNIMPLEMENTED",
+            "INTERNAL",
+            "UNAVAILABLE",
+            "DATA_LOSS",
+            "UNAUTHENTICATED",
+        ];
+
+        match NAMES.get(self.code as usize) {
+            Some(name) => write!(f, "{name}")?,
+            None => write!(f, "status {}", self.code)?,
+        }
+
+        match self.message {
+            Some(ref message) if !message.is_empty() => write!(f, ": {message}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A message body after the transfer and content codings are removed.
+#[derive(Clone)]
+pub struct Body {
+    pub data: Vec<u8>,
+    /// The size of the body as it was sent, with the content codings.
+    pub wire_size: usize,
+    /// The content codings of the body, like `gzip` or `gzip, br`.
+    pub encoding: Option<String>,
+    /// The body could not be decoded, it is shown as it was sent.
+    pub decode_error: Option<String>,
+    /// The fields of a form body.
+    pub form: Option<Form>,
+    /// The `charset` parameter of the Content-Type, as it was sent.
+    pub charset: Option<String>,
+}
+
+/// The fields of a form posted by the client.
+#[derive(Clone, Debug)]
+pub enum Form {
+    /// The parts of a `multipart/form-data` body, the parts before an error are kept.
+    Multipart {
+        parts: Vec<FormPart>,
+        error: Option<String>,
+    },
+    /// The names and values of an `application/x-www-form-urlencoded` body, they are
+    /// decoded to text with the charset of the body.
+    UrlEncoded(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+/// A part of a `multipart/form-data` body, a form field or an uploaded file.
+#[derive(Clone, Debug, Default)]
+pub struct FormPart {
+    pub headers: Headers,
+    /// The name of the form field from the `Content-Disposition` header.
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    /// The `charset` parameter of the Content-Type of the part.
+    pub charset: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl FormPart {
+    /// The header line of the part with the field name, file name, size and type.
+    fn title(&self) -> String {
+        let mut title = match self.name {
+            Some(ref name) => format!("{name:?}"),
+            None => "[no name]".to_string(),
+        };
+
+        if let Some(ref filename) = self.filename {
+            title.push_str(&format!(" file {filename:?}"));
+        }
+
+        title.push_str(&format!(" ({} bytes", self.data.len()));
+
+        if let Some(content_type) = self.headers.get("content-type") {
+            title.push_str(&format!(", {content_type}"));
+        }
+
+        title.push(')');
+        title
+    }
+}
+
+impl Body {
+    /// Gives back the body as text if it has no control characters other than whitespace
+    /// when it is decoded with the selected or the declared charset.
+    pub fn text(&self, selected: Option<Charset>) -> Option<charset::Decoded> {
+        charset::decode_text(&self.data, self.charset.as_deref(), selected)
+    }
+
+    /// The charset of the body if it is known, otherwise UTF-8.
+    fn charset(&self, selected: Option<Charset>) -> Charset {
+        selected
+            .or_else(|| self.charset.as_deref().and_then(Charset::from_label))
+            .unwrap_or(Charset::Utf8)
+    }
 }
 
 impl std::fmt::Debug for HttpStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("HttpStream")
             .field("id", &self.id)
+            .field("interface", &self.interface)
             .field("source_addr", &self.source_addr)
             .field("source_port", &self.source_port)
             .field("dest_addr", &self.dest_addr)
@@ -50,24 +491,85 @@ impl From<&HttpStream> for Row<'_> {
         Row::new(vec![
             format!("{:5}", value.id),
             format!("{:10}", value.timestamp),
+            value.interface.clone(),
             format!("{}:{}", value.source_addr, value.source_port),
             format!("{}:{}", value.dest_addr, value.dest_port),
-            match value.parsed_request {
-                None => "Cannot parse request".to_string(),
-                Some(ref pr) => format!("{} {}", pr.method, pr.path),
-            },
+            value.summary(),
         ])
     }
 }
 
 impl HttpStream {
+    /// The request line with the problems of the exchange, shown in the stream list.
+    fn summary(&self) -> String {
+        let mut summary = match self.parsed_request {
+            None => "Cannot parse request".to_string(),
+            // Absolute-form targets of proxy requests are shown with their host
+            Some(ref pr) => match pr.authority {
+                Some(ref authority) => format!("{} {authority}{}", pr.method, pr.path),
+                None => format!("{} {}", pr.method, pr.path),
+            },
+        };
+
+        if let Some(ref tunnel) = self.tunnel {
+            summary.push_str(&format!(" [{tunnel}]"));
+        }
+
+        if let Some(ref status) = self.grpc_status {
+            summary.push_str(&format!(" [gRPC {status}]"));
+        }
+
+        if self.in_progress {
+            summary.push_str(" [in progress]");
+        }
+
+        if self.missing_bytes > 0 {
+            summary.push_str(" [gap]");
+        }
+
+        if let Some(ref e) = self.error {
+            summary.push_str(&format!(" [{e}]"));
+        }
+
+        summary
+    }
+
     pub fn write_to_text(&self, text: &mut Text) {
         let green = Style::new().fg(Color::Green).add_modifier(Modifier::BOLD);
         let red = Style::new().fg(Color::LightRed);
+        let yellow = Style::new().fg(Color::Yellow);
+
+        if self.missing_bytes > 0 {
+            text.push_line(Line::styled(
+                format!("{} bytes are missing from the capture", self.missing_bytes),
+                yellow,
+            ));
+        }
+
+        if let Some(ref e) = self.error {
+            text.push_line(Line::styled(format!("Error: {e}"), red));
+        }
+
+        for event in &self.events {
+            text.push_line(Line::styled(format!("Event: {event}"), yellow));
+        }
+
+        if let Some(ref status) = self.grpc_status {
+            let style = if status.is_ok() { green } else { red };
+
+            text.push_line(Line::styled(format!("gRPC status: {status}"), style));
+        }
 
         if let Some(ref pr) = self.parsed_request {
             text.push_line(Line::styled(format!("{} {}\n", pr.method, pr.path), green));
 
+            if let Some(ref authority) = pr.authority {
+                text.push_line(Line::styled(
+                    format!("[absolute-form target on {authority}]"),
+                    Color::Gray,
+                ));
+            }
+
             for header in &pr.headers {
                 let mut line = Line::styled(format!("{}:", header.0), red);
 
@@ -79,7 +581,44 @@ impl HttpStream {
             text.extend(Text::raw("\n"));
 
             if let Some(ref body) = pr.body {
-                text.extend(Text::raw(body.clone()));
+                write_body(text, body, self.charset);
+            }
+
+            if !pr.trailers.is_empty() {
+                text.push_line(Line::styled("Trailers", green));
+
+                for trailer in &pr.trailers {
+                    let mut line = Line::styled(format!("{}:", trailer.0), red);
+
+                    line.push_span(Span::styled(format!(" {}\n", trailer.1), Color::Gray));
+
+                    text.push_line(line);
+                }
+            }
+
+            if pr.truncated {
+                text.push_line(Line::styled("[body truncated]", yellow));
+            }
+
+            text.extend(Text::raw("\n"));
+        }
+
+        if !self.unparsed_request.is_empty() {
+            write_unparsed(text, "Unparsed request bytes", &self.unparsed_request);
+        }
+
+        for interim in &self.interim_responses {
+            text.push_line(Line::styled(
+                format!("{} {} (interim)", interim.code, interim.version),
+                green,
+            ));
+
+            for header in &interim.headers {
+                let mut line = Line::styled(format!("{}:", header.0), red);
+
+                line.push_span(Span::styled(format!(" {}\n", header.1), Color::Gray));
+
+                text.push_line(line);
             }
 
             text.extend(Text::raw("\n"));
@@ -102,7 +641,107 @@ impl HttpStream {
             text.extend(Text::raw("\n"));
 
             if let Some(ref body) = resp.body {
-                text.extend(Text::raw(body.clone()));
+                write_body(text, body, self.charset);
+            }
+
+            if !resp.trailers.is_empty() {
+                text.push_line(Line::styled("Trailers", green));
+
+                for trailer in &resp.trailers {
+                    let mut line = Line::styled(format!("{}:", trailer.0), red);
+
+                    line.push_span(Span::styled(format!(" {}\n", trailer.1), Color::Gray));
+
+                    text.push_line(line);
+                }
+            }
+
+            if resp.truncated {
+                text.push_line(Line::styled("[body truncated]", yellow));
+            }
+
+            if resp.close_delimited {
+                text.push_line(Line::styled("[body delimited by connection close]", yellow));
+            }
+
+            if self.in_progress {
+                let status = match resp.body {
+                    Some(ref body) => format!(
+                        "[response in progress, {} bytes of the body received]",
+                        body.wire_size
+                    ),
+                    // The connection is upgraded to another protocol, like WebSocket
+                    None => "[connection open]".to_string(),
+                };
+
+                text.push_line(Line::styled(status, yellow));
+            }
+        }
+
+        if !self.unparsed_response.is_empty() {
+            text.extend(Text::raw("\n"));
+
+            write_unparsed(text, "Unparsed response bytes", &self.unparsed_response);
+        }
+
+        if !self.grpc_messages.is_empty() {
+            text.extend(Text::raw("\n"));
+            text.push_line(Line::styled(
+                format!("gRPC messages ({})", self.grpc_messages.len()),
+                green,
+            ));
+
+            for message in &self.grpc_messages {
+                text.push_line(Line::styled(message.title(), Color::Cyan));
+
+                if let Some(ref e) = message.error {
+                    text.push_line(Line::styled(format!("[{e}]"), yellow));
+                }
+
+                match message.decoded {
+                    Some(ref decoded) => text.extend(Text::raw(decoded.clone())),
+                    None => write_hexdump(text, &message.data),
+                }
+            }
+        }
+
+        if !self.sse_events.is_empty() {
+            text.extend(Text::raw("\n"));
+            text.push_line(Line::styled(
+                format!("Server-sent events ({})", self.sse_events.len()),
+                green,
+            ));
+
+            for event in &self.sse_events {
+                text.push_line(Line::styled(event.title(), Color::Cyan));
+                text.extend(Text::raw(event.data.clone()));
+            }
+        }
+
+        if let Some(ref tunnel) = self.tunnel {
+            text.extend(Text::raw("\n"));
+            text.push_line(Line::styled(format!("CONNECT {tunnel}"), yellow));
+        }
+
+        if !self.messages.is_empty() {
+            text.extend(Text::raw("\n"));
+            text.push_line(Line::styled(
+                format!("WebSocket messages ({})", self.messages.len()),
+                green,
+            ));
+
+            for message in &self.messages {
+                text.push_line(Line::styled(message.title(), Color::Cyan));
+
+                if let Some(ref e) = message.error {
+                    text.push_line(Line::styled(format!("[{e}]"), yellow));
+                }
+
+                match (message.close_reason(), as_text(&message.data)) {
+                    (Some(reason), _) => text.push_line(Line::raw(reason)),
+                    (None, Some(message_text)) => text.extend(Text::raw(message_text.to_string())),
+                    (None, None) => write_hexdump(text, &message.data),
+                }
             }
         }
     }
@@ -111,6 +750,10 @@ impl HttpStream {
         &self,
         mut writer: std::io::BufWriter<File>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(ref e) = self.error {
+            writer.write_fmt(format_args!("Error: {}\n", e))?;
+        }
+
         if let Some(ref req) = self.parsed_request {
             writer.write_fmt(format_args!(
                 "HTTP 1.{} {} {}\n",
@@ -124,8 +767,24 @@ impl HttpStream {
             writer.write_all("\n".as_bytes())?;
 
             if let Some(ref body) = req.body {
-                writer.write_all(body.as_bytes())?;
+                writer.write_all(&body.data)?;
+            }
+
+            for trailer in &req.trailers {
+                writer.write_fmt(format_args!("{}: {}\n", trailer.0, trailer.1))?;
+            }
+        }
+
+        writer.write_all(&self.unparsed_request)?;
+
+        for interim in &self.interim_responses {
+            writer.write_fmt(format_args!("{}\n", interim.code))?;
+
+            for header in &interim.headers {
+                writer.write_fmt(format_args!("{}: {}\n", header.0, header.1))?;
             }
+
+            writer.write_all("\n".as_bytes())?;
         }
 
         if let Some(ref resp) = self.parsed_response {
@@ -138,10 +797,228 @@ impl HttpStream {
             writer.write_all("\n".as_bytes())?;
 
             if let Some(ref body) = resp.body {
-                writer.write_all(body.as_bytes())?;
+                writer.write_all(&body.data)?;
             }
+
+            for trailer in &resp.trailers {
+                writer.write_fmt(format_args!("{}: {}\n", trailer.0, trailer.1))?;
+            }
+        }
+
+        writer.write_all(&self.unparsed_response)?;
+
+        if let Some(ref tunnel) = self.tunnel {
+            writer.write_fmt(format_args!("CONNECT {tunnel}\n"))?;
+        }
+
+        for message in &self.messages {
+            writer.write_fmt(format_args!("\n{}\n", message.title()))?;
+            writer.write_all(&message.data)?;
+        }
+
+        if let Some(ref status) = self.grpc_status {
+            writer.write_fmt(format_args!("gRPC status: {status}\n"))?;
+        }
+
+        for message in &self.grpc_messages {
+            writer.write_fmt(format_args!("\n{}\n", message.title()))?;
+
+            match message.decoded {
+                Some(ref decoded) => writer.write_fmt(format_args!("{decoded}\n"))?,
+                None => writer.write_all(&message.data)?,
+            }
+        }
+
+        for event in &self.sse_events {
+            writer.write_fmt(format_args!("\n{}\n{}\n", event.title(), event.data))?;
         }
 
         Ok(())
     }
 }
+
+fn write_body(text: &mut Text, body: &Body, selected: Option<Charset>) {
+    let yellow = Style::new().fg(Color::Yellow);
+
+    if let Some(ref encoding) = body.encoding {
+        text.push_line(Line::styled(
+            format!(
+                "[{} bytes decoded, {} bytes on the wire, {}]",
+                body.data.len(),
+                body.wire_size,
+                encoding
+            ),
+            Color::Gray,
+        ));
+    }
+
+    if let Some(ref e) = body.decode_error {
+        text.push_line(Line::styled(format!("[{e}, showing the raw body]"), yellow));
+    }
+
+    if let Some(ref label) = body.charset {
+        if selected.is_none() && Charset::from_label(label).is_none() {
+            text.push_line(Line::styled(format!("[unknown charset {label:?}]"), yellow));
+        }
+    }
+
+    match body.form {
+        Some(ref form) if body.decode_error.is_none() => {
+            write_form(text, form, body.charset(selected), selected)
+        }
+        _ => match body.text(selected) {
+            Some(decoded) => {
+                if !body.data.is_empty() {
+                    text.push_line(Line::styled(
+                        format!("[{}, {}]", decoded.charset, decoded.source),
+                        Color::Gray,
+                    ));
+                }
+
+                text.extend(Text::raw(decoded.text));
+            }
+            None => write_hexdump(text, &body.data),
+        },
+    }
+}
+
+/// The fields of url-encoded forms are decoded with the charset of the body, the parts of
+/// multipart forms with their own charset unless the user selected one.
+fn write_form(text: &mut Text, form: &Form, charset: Charset, selected: Option<Charset>) {
+    let green = Style::new().fg(Color::Green).add_modifier(Modifier::BOLD);
+    let red = Style::new().fg(Color::LightRed);
+
+    match form {
+        Form::Multipart { parts, error } => {
+            text.push_line(Line::styled(
+                format!("Form data ({} parts)", parts.len()),
+                green,
+            ));
+
+            for part in parts {
+                text.push_line(Line::styled(part.title(), Color::Cyan));
+
+                for header in &part.headers {
+                    let mut line = Line::styled(format!("{}:", header.0), red);
+
+                    line.push_span(Span::styled(format!(" {}", header.1), Color::Gray));
+
+                    text.push_line(line);
+                }
+
+                write_preview(text, &part.data, part.charset.as_deref(), selected);
+            }
+
+            if let Some(ref e) = error {
+                text.push_line(Line::styled(format!("[{e}]"), Color::Yellow));
+            }
+        }
+        Form::UrlEncoded(fields) => {
+            text.push_line(Line::styled(
+                format!("Form fields ({}, {charset})", fields.len()),
+                green,
+            ));
+
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(name, value)| (charset.decode(name), charset.decode(value)))
+                .collect();
+
+            let width = fields
+                .iter()
+                .map(|(name, _)| name.chars().count())
+                .max()
+                .unwrap_or_default()
+                .min(32);
+
+            for (name, value) in &fields {
+                let mut line = Line::styled(format!("{name:width$}"), red);
+
+                line.push_span(Span::styled(format!(" = {value}"), Color::Gray));
+
+                text.push_line(line);
+            }
+        }
+    }
+}
+
+/// Show the beginning of the data as text if it is printable, otherwise as hex dump.
+fn write_preview(text: &mut Text, data: &[u8], declared: Option<&str>, selected: Option<Charset>) {
+    let preview = &data[..data.len().min(MAX_PREVIEW_BYTES)];
+    // A multibyte character can be cut at the end of the preview
+    let valid = match std::str::from_utf8(preview) {
+        Ok(_) => preview,
+        Err(e) if e.error_len().is_none() => &preview[..e.valid_up_to()],
+        Err(_) => preview,
+    };
+
+    match charset::decode_text(valid, declared, selected) {
+        Some(decoded) => text.extend(Text::raw(decoded.text)),
+        None => {
+            for line in common::hexdump(preview) {
+                text.push_line(Line::raw(line));
+            }
+        }
+    }
+
+    if preview.len() < data.len() {
+        text.push_line(Line::styled(
+            format!("[{} more bytes]", data.len() - preview.len()),
+            Color::Gray,
+        ));
+    }
+}
+
+/// The time of a packet in the local time zone, with milliseconds.
+fn format_time(timestamp: Duration) -> String {
+    DateTime::from_timestamp(timestamp.as_secs() as i64, timestamp.subsec_nanos())
+        .map(|time| {
+            time.with_timezone(&Local)
+                .format("%H:%M:%S%.3f")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+fn as_text(data: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(data).ok()?;
+
+    text.chars()
+        .all(|c| !c.is_control() || c.is_whitespace())
+        .then_some(text)
+}
+
+/// Show bytes which could not be parsed, as text if they are printable.
+fn write_unparsed(text: &mut Text, title: &str, data: &[u8]) {
+    text.push_line(Line::styled(
+        format!("{title} ({} bytes)", data.len()),
+        Style::new().fg(Color::Yellow),
+    ));
+
+    match as_text(data) {
+        Some(s) => text.extend(Text::raw(s.to_string())),
+        None => write_hexdump(text, data),
+    }
+
+    text.extend(Text::raw("\n"));
+}
+
+fn write_hexdump(text: &mut Text, data: &[u8]) {
+    text.push_line(Line::styled(
+        format!("[binary, {} bytes]", data.len()),
+        Color::Gray,
+    ));
+
+    let dump_len = data.len().min(MAX_HEXDUMP_BYTES);
+
+    for line in common::hexdump(&data[..dump_len]) {
+        text.push_line(Line::raw(line));
+    }
+
+    if dump_len < data.len() {
+        text.push_line(Line::styled(
+            format!("[{} more bytes]", data.len() - dump_len),
+            Color::Gray,
+        ));
+    }
+}